@@ -1,7 +1,7 @@
 "use client";
 
 import React, { useEffect, useRef, useState } from "react";
-import { useWebSocket } from "../hooks/useWebSocket";
+import { useWebSocket, GameMap } from "../hooks/useWebSocket";
 
 // Define types for our game objects
 interface GameObject {
@@ -28,7 +28,7 @@ interface TrailPoint {
 
 const GameCanvas: React.FC = () => {
   const canvasRef = useRef<HTMLCanvasElement>(null);
-  const { connected, gameState, sendKeyPress } = useWebSocket();
+  const { connected, gameState, map, sendKeyPress } = useWebSocket();
   const [canvasSize, setCanvasSize] = useState({ width: 750, height: 550 });
   const [playerImage, setPlayerImage] = useState<HTMLImageElement | null>(null);
   const [trail, setTrail] = useState<TrailPoint[]>([]);
@@ -124,6 +124,11 @@ const GameCanvas: React.FC = () => {
 
     // Draw the game world (grid background)
     drawGameWorld(ctx, canvas.width, canvas.height);
+
+    // Draw the walls of the map the node enforces
+    if (map) {
+      drawWalls(ctx, map, canvas.height);
+    }
     
     // Draw the player
     drawPlayer(ctx, player, playerImage, canvas.height);
@@ -131,11 +136,13 @@ const GameCanvas: React.FC = () => {
     // Draw game stats
     drawGameStats(ctx, { player, connected, proofStatus: gameState.proofStatus }, canvas.height);
     
-    // Draw verified trail from WebSocket if available
-    if (gameState.trail && gameState.trail.length > 0) {
-      drawVerifiedTrail(ctx, gameState.trail, canvas.height);
-    }
-  }, [gameState, canvasSize, playerImage, trail, connected, player]);
+    // Draw every remote player's verified trail from WebSocket
+    Object.values(gameState.players).forEach((remote, index) => {
+      if (remote.trail.length > 0) {
+        drawVerifiedTrail(ctx, remote.trail, canvas.height, TRAIL_COLORS[index % TRAIL_COLORS.length]);
+      }
+    });
+  }, [gameState, canvasSize, playerImage, trail, connected, player, map]);
 
   // Handle keyboard input
   useEffect(() => {
@@ -231,12 +238,33 @@ function drawGameWorld(
 
 
 
+// Draw every wall cell of the map
+function drawWalls(ctx: CanvasRenderingContext2D, map: GameMap, canvasHeight: number) {
+  const GRID_SCALE_FACTOR = 50; // Must match the value in useWebSocket.tsx
+
+  ctx.fillStyle = "#374151";
+  map.walls.forEach((isWall, index) => {
+    if (!isWall) return;
+    const x = index % map.width;
+    const y = Math.floor(index / map.width);
+    ctx.fillRect(
+      x * GRID_SCALE_FACTOR,
+      canvasHeight - (y + 1) * GRID_SCALE_FACTOR,
+      GRID_SCALE_FACTOR,
+      GRID_SCALE_FACTOR
+    );
+  });
+}
+
+// RGB colors used to tell remote players' trails apart
+const TRAIL_COLORS = ["0, 255, 0", "255, 165, 0", "0, 191, 255", "255, 0, 255", "255, 255, 0"];
+
 // Draw the verified trail from the ZK proof
-function drawVerifiedTrail(ctx: CanvasRenderingContext2D, trail: [number, number][], canvasHeight: number) {
+function drawVerifiedTrail(ctx: CanvasRenderingContext2D, trail: [number, number][], canvasHeight: number, color: string) {
   
   trail.forEach((point, index) => {
     const opacity = (index / (trail.length * 1.5)) + 0.6;
-    ctx.fillStyle = `rgba(0, 255, 0, ${opacity})`;
+    ctx.fillStyle = `rgba(${color}, ${opacity})`;
     ctx.beginPath();
     ctx.arc(point[0] + 20, canvasHeight - point[1] - 20, 10 * opacity, 0, Math.PI * 2);
     ctx.fill();
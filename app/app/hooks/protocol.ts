@@ -0,0 +1,23 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+export type ClientMessage = { "type": "hello", version: number, } | { "type": "key_press", key: Key, } | { "type": "list_lobbies" } | { "type": "create_lobby", name?: string, } | { "type": "join_lobby", name: string, } | { "type": "leave_lobby" } | { "type": "resync" };
+
+export type ErrorCode = "unsupported_version" | "handshake_required" | "bad_request";
+
+export type Key = "up" | "down" | "left" | "right" | "test";
+
+export type LobbyListing = { name: string, players: number, rules: LobbyRules, mapHash: string, };
+
+export type LobbyRules = { revealPercent: number, };
+
+export type LocalState = { position: Position, proofStatus: string, processing: boolean, lastBatchSize: number, };
+
+export type Position = { x: number, y: number, };
+
+export type RemotePlayer = { trail: Array<[number, number]>, lastProofTime: number, batchCount: number, status: string, lastSequence: number | null, missedBatches: number, name: string, customUrl: string, lastSeen: number, online: boolean, };
+
+export type RosterEntry = { peerId: string, name: string, customUrl: string, lastSeen: number, status: string, };
+
+export type ServerMessage = { "type": "welcome", version: number, nodeName: string, } | { "type": "state_update" } & StateSnapshot | { "type": "local_update" } & LocalState | { "type": "player_update", peerId: string, player: RemotePlayer, } | { "type": "map_info", map: { width: number, height: number, walls: Array<boolean> }, } | { "type": "roster_update", roster: Array<RosterEntry>, } | { "type": "lobby_update", current: string | null, lobbies: Array<LobbyListing>, } | { "type": "lobby_error", message: string, } | { "type": "node_info", peerId: string, name: string, customUrl: string, } | { "type": "p2p_connection", peerId: string, address: string, isIncoming: boolean, timestamp: number, } | { "type": "error", code: ErrorCode, message: string, };
+
+export type StateSnapshot = { position: Position, proofStatus: string, processing: boolean, lastBatchSize: number, players: { [key in string]?: RemotePlayer }, nodeName: string, };
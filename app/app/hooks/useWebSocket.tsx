@@ -2,6 +2,26 @@
 
 import { useState, useEffect, useCallback, useRef } from 'react';
 
+import type {
+  ClientMessage,
+  Key,
+  LobbyListing,
+  RemotePlayer,
+  RosterEntry,
+  ServerMessage,
+} from './protocol';
+
+// Protocol version this app speaks; must match PROTOCOL_VERSION in src/protocol.rs
+const PROTOCOL_VERSION = 2;
+
+export type { LobbyListing, RemotePlayer, RosterEntry };
+
+// Rules every player in a lobby proves against
+export type GameParams = LobbyListing['rules'];
+
+// Map shared with the node; walls are row-major starting from the bottom row
+export type GameMap = Extract<ServerMessage, { type: 'map_info' }>['map'];
+
 interface WebSocketState {
   position: {
     x: number;
@@ -10,7 +30,8 @@ interface WebSocketState {
   proofStatus: string;
   processing: boolean;
   lastBatchSize: number;
-  trail: [number, number][];
+  // Remote players keyed by PeerId
+  players: Record<string, RemotePlayer>;
 }
 
 // Constants for grid scaling
@@ -20,6 +41,23 @@ const GRID_SCALE_FACTOR = 50; // Each game grid unit = 20 ZK proof grid units
 const GRID_LIMIT_X = 720;
 const GRID_LIMIT_Y = 520;
 
+// Scale a position from the ZK proof grid to the game grid, within the grid limits.
+// The server still uses the original coordinate system (0,0 at top-left),
+// and we keep the same coordinate system for internal state.
+const scalePosition = (position: { x: number; y: number }) => ({
+  x: Math.min(GRID_LIMIT_X, Math.max(0, position.x * GRID_SCALE_FACTOR)),
+  y: Math.min(GRID_LIMIT_Y, Math.max(0, position.y * GRID_SCALE_FACTOR))
+});
+
+// Scale a remote player's trail the same way
+const scalePlayer = (remote: RemotePlayer): RemotePlayer => ({
+  ...remote,
+  trail: remote.trail.map(([x, y]) => [
+    Math.min(GRID_LIMIT_X, Math.max(0, x * GRID_SCALE_FACTOR)),
+    Math.min(GRID_LIMIT_Y, Math.max(0, y * GRID_SCALE_FACTOR))
+  ] as [number, number])
+});
+
 export const useWebSocket = () => {
   const [connected, setConnected] = useState(false);
   const [gameState, setGameState] = useState<WebSocketState>({
@@ -27,9 +65,17 @@ export const useWebSocket = () => {
     proofStatus: "Connecting...",
     processing: false,
     lastBatchSize: 0,
-    trail: []
+    players: {}
   });
   
+  const [map, setMap] = useState<GameMap | null>(null);
+  const [roster, setRoster] = useState<RosterEntry[]>([]);
+  const [lobbies, setLobbies] = useState<LobbyListing[]>([]);
+  const [currentLobby, setCurrentLobby] = useState<string | null>(null);
+  const [lobbyError, setLobbyError] = useState<string | null>(null);
+  // Set when the node refuses our protocol version or a message we sent
+  const [protocolError, setProtocolError] = useState<string | null>(null);
+  
   const socketRef = useRef<WebSocket | null>(null);
   
   // Initialize WebSocket connection
@@ -44,7 +90,9 @@ export const useWebSocket = () => {
 
       ws.onopen = () => {
         console.log('WebSocket connected');
-        setConnected(true);
+        // The node sends nothing until we say which protocol version we speak
+        const hello: ClientMessage = { type: 'hello', version: PROTOCOL_VERSION };
+        ws.send(JSON.stringify(hello));
       };
       
       ws.onclose = () => {
@@ -60,56 +108,84 @@ export const useWebSocket = () => {
       };
       
       ws.onmessage = (event) => {
+        let data: ServerMessage;
         try {
-          const data = JSON.parse(event.data);
-          
-          if (data.type === 'state_update') {
-
-            console.log("state_update", data);
-            // Scale the position coordinates from ZK proof grid to game grid
-            // and enforce grid limits
-            const scaledX = data.position.x * GRID_SCALE_FACTOR;
-            // Note: The server is still using the original coordinate system (0,0 at top-left)
-            // We need to keep the same coordinate system for internal state
-            const scaledY = data.position.y * GRID_SCALE_FACTOR;
-            
-            // Log trail data for debugging
-            if (data.trail) {
-              console.log(`Received trail data with ${data.trail.length} points`);
-            }
-            
-            const scaledTrail = data.trail ? data.trail.map(([x, y]: [number, number]) => [
-              Math.min(GRID_LIMIT_X, Math.max(0, x * GRID_SCALE_FACTOR)),
-              Math.min(GRID_LIMIT_Y, Math.max(0, y * GRID_SCALE_FACTOR))
-            ] as [number, number]) : [];
-            
-            // Log the scaled trail
-            if (scaledTrail.length > 0) {
-              console.log(`Scaled trail has ${scaledTrail.length} points. First point: ${scaledTrail[0]}`);
-            }
-            
+          data = JSON.parse(event.data);
+        } catch (error) {
+          console.error('Error parsing WebSocket message:', error);
+          return;
+        }
+
+        switch (data.type) {
+          case 'welcome':
+            console.log(`Connected to ${data.nodeName} (protocol v${data.version})`);
+            setConnected(true);
+            setProtocolError(null);
+            break;
+          case 'state_update': {
+            // Full snapshot: replaces everything we had
+            const scaledPlayers: Record<string, RemotePlayer> = {};
+            Object.entries(data.players).forEach(([playerId, remote]) => {
+              if (!remote) return;
+              scaledPlayers[playerId] = scalePlayer(remote);
+            });
+
             setGameState({
-              position: {
-                x: Math.min(GRID_LIMIT_X, Math.max(0, scaledX)),
-                y: Math.min(GRID_LIMIT_Y, Math.max(0, scaledY))
-              },
+              position: scalePosition(data.position),
               proofStatus: data.proofStatus,
               processing: data.processing,
               lastBatchSize: data.lastBatchSize,
-              // Scale the trail coordinates as well
-              trail: scaledTrail
+              players: scaledPlayers
             });
-          } else if (data.type === "node_info") {
-            console.log(`Received node info: ${data.name} (${data.peer_id})`);
-            console.log(`Custom URL: ${data.custom_url}`);
+            break;
+          }
+          case 'local_update':
+            setGameState(prev => ({
+              ...prev,
+              position: scalePosition(data.position),
+              proofStatus: data.proofStatus,
+              processing: data.processing,
+              lastBatchSize: data.lastBatchSize
+            }));
+            break;
+          case 'player_update': {
+            const { peerId, player } = data;
+            setGameState(prev => ({
+              ...prev,
+              players: { ...prev.players, [peerId]: scalePlayer(player) }
+            }));
+            break;
+          }
+          case 'map_info':
+            setMap(data.map);
+            break;
+          case 'roster_update':
+            setRoster(data.roster);
+            break;
+          case 'lobby_update':
+            setLobbies(data.lobbies);
+            setCurrentLobby(data.current);
+            setLobbyError(null);
+            break;
+          case 'lobby_error':
+            setLobbyError(data.message);
+            break;
+          case 'error':
+            console.error(`Node reported ${data.code}: ${data.message}`);
+            setProtocolError(data.message);
+            break;
+          case 'node_info':
+            console.log(`Received node info: ${data.name} (${data.peerId})`);
+            console.log(`Custom URL: ${data.customUrl}`);
 
-            localStorage.setItem("endpoint", data.custom_url);
+            localStorage.setItem("endpoint", data.customUrl);
 
             window.location.href = "/verify";
             // Use the custom URL as needed
-          }
-        } catch (error) {
-          console.error('Error parsing WebSocket message:', error);
+            break;
+          case 'p2p_connection':
+            console.log(`Peer ${data.peerId} connected via ${data.address}`);
+            break;
         }
       };
       
@@ -127,15 +203,12 @@ export const useWebSocket = () => {
   }, []);
   
   // Function to send key presses to the server
-  const sendKeyPress = useCallback((key: 'up' | 'down' | 'left' | 'right' | 'test') => {
+  const sendKeyPress = useCallback((key: Key) => {
     if (socketRef.current && socketRef.current.readyState === WebSocket.OPEN) {
       // The server still expects the original key commands
       // The scaling is handled when receiving the updated position
-      const message = JSON.stringify({
-        type: 'key_press',
-        key
-      });
-      socketRef.current.send(message);
+      const message: ClientMessage = { type: 'key_press', key };
+      socketRef.current.send(JSON.stringify(message));
       
       // For a more responsive feel, we can update the local state immediately
       // This is just a visual update, the actual position will be corrected when the server responds
@@ -151,9 +224,35 @@ export const useWebSocket = () => {
     }
   }, [gameState]);
   
+  // Lobby commands; the node answers with a lobby_update or lobby_error
+  const sendCommand = useCallback((message: ClientMessage) => {
+    if (socketRef.current && socketRef.current.readyState === WebSocket.OPEN) {
+      socketRef.current.send(JSON.stringify(message));
+    }
+  }, []);
+
+  const listLobbies = useCallback(() => sendCommand({ type: 'list_lobbies' }), [sendCommand]);
+  // Without a name the node makes up a short code to share
+  const createLobby = useCallback((name?: string) => sendCommand({ type: 'create_lobby', name }), [sendCommand]);
+  const joinLobby = useCallback((name: string) => sendCommand({ type: 'join_lobby', name }), [sendCommand]);
+  const leaveLobby = useCallback(() => sendCommand({ type: 'leave_lobby' }), [sendCommand]);
+  // Ask for a full snapshot instead of waiting for the next delta
+  const resync = useCallback(() => sendCommand({ type: 'resync' }), [sendCommand]);
+
   return {
     connected,
     gameState,
-    sendKeyPress
+    map,
+    roster,
+    lobbies,
+    currentLobby,
+    lobbyError,
+    protocolError,
+    sendKeyPress,
+    listLobbies,
+    createLobby,
+    joinLobby,
+    leaveLobby,
+    resync
   };
 }; 
\ No newline at end of file
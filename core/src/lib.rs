@@ -12,14 +12,252 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::ops::Range;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Length of the secret salt mixed into position commitments
+pub const SALT_LEN: usize = 16;
+
+// Whole-block position on the game grid.
+// Serialized as an `[x, y]` pair so JSON consumers that expect the
+// old tuple trail keep working.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash, Serialize)]
+#[serde(from = "(i32, i32)", into = "(i32, i32)")]
+pub struct GridPos {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl GridPos {
+    pub const fn new(x: i32, y: i32) -> Self {
+        Self { x, y }
+    }
+
+    // Position moved by the given number of blocks
+    pub fn offset(self, dx: i32, dy: i32) -> Self {
+        Self::new(self.x + dx, self.y + dy)
+    }
+}
+
+impl From<(i32, i32)> for GridPos {
+    fn from((x, y): (i32, i32)) -> Self {
+        Self::new(x, y)
+    }
+}
+
+impl From<GridPos> for (i32, i32) {
+    fn from(pos: GridPos) -> Self {
+        (pos.x, pos.y)
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Outputs {
     // Remove final position from the public output
-    // pub position_x: f32,
-    // pub position_y: f32,
+    // pub position: GridPos,
     
     // Only share the trail of previous positions
-    pub trail_positions: Vec<(f32, f32)>,
+    pub trail_positions: Vec<GridPos>,
+
+    // Salted hashes of the start and end positions of this batch.
+    // A batch's start commitment must equal the previous batch's end commitment.
+    pub start_commitment: [u8; 32],
+    pub end_commitment: [u8; 32],
+
+    // Hash of the map the movement was checked against
+    pub map_hash: [u8; 32],
+
+    // Game rules the batch was proven under
+    pub params: GameParams,
+
+    // Seed the revealed window was derived from, the other player's batch
+    // mixed into it, and the indices (into the batch's positions) that ended
+    // up in `trail_positions`
+    pub reveal_seed: [u8; 32],
+    pub reveal_beacon: Option<RevealBeacon>,
+    pub reveal_indices: Vec<u32>,
+
+    // Counts the runs of the player's node: each run proves under a higher
+    // epoch than the last, continuing the same position chain. The batch
+    // sequence number starts at 0 in every epoch and increases by one per batch.
+    pub epoch: u64,
+    pub sequence: u64,
+
+    // Who proved the batch, as a PeerId, and the match it was played in, so
+    // a receipt can't be passed off as another player's or replayed elsewhere
+    pub player_id: String,
+    pub match_id: [u8; 32],
+}
+
+// Another player's published batch whose journal is mixed into a reveal seed.
+// Its digest can't be known before that batch is proven, so the prover can't
+// line up a seed ahead of time.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct RevealBeacon {
+    pub player_id: String,
+    pub epoch: u64,
+    pub sequence: u64,
+    pub journal_digest: [u8; 32],
+}
+
+// Game rules every player must prove against, committed in the journal
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct GameParams {
+    // Percentage of each batch's trail revealed to other players
+    pub reveal_percent: u8,
+}
+
+impl Default for GameParams {
+    fn default() -> Self {
+        Self { reveal_percent: 50 }
+    }
+}
+
+// Digest of a proof journal; the previous digest seeds the next reveal window
+pub fn journal_digest(journal: &[u8]) -> [u8; 32] {
+    Sha256::digest(journal).into()
+}
+
+// Reveal seed for the batch that follows the journal with the given digest.
+// A player's first batch uses an all-zero digest, and a batch proven before
+// its player has verified anyone else's has no beacon.
+pub fn reveal_seed(previous_journal_digest: &[u8; 32], beacon: Option<&RevealBeacon>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"footsteps-reveal");
+    hasher.update(previous_journal_digest);
+    if let Some(beacon) = beacon {
+        hasher.update(beacon.journal_digest);
+    }
+    hasher.finalize().into()
+}
+
+// Pick the contiguous window of `len` positions to reveal. Only the seed and
+// the length decide it, so nothing the prover picks after the seed is fixed
+// moves the window.
+pub fn reveal_window(seed: &[u8; 32], len: usize, params: &GameParams) -> Range<usize> {
+    let count = (len * params.reveal_percent.min(100) as usize) / 100;
+    let count = if len > 0 && params.reveal_percent > 0 { count.max(1) } else { 0 };
+    if count == 0 {
+        return 0..0;
+    }
+
+    let digest: [u8; 32] = Sha256::digest(seed).into();
+    let mut offset_bytes = [0u8; 8];
+    offset_bytes.copy_from_slice(&digest[..8]);
+
+    let start = (u64::from_le_bytes(offset_bytes) % (len - count + 1) as u64) as usize;
+    start..start + count
+}
+
+// Map every player moves on, shared between the guest and the host.
+// The origin is the bottom-left cell and y grows upwards. Maps arrive over
+// the wire, so the wall flags are checked against the size when deserialized.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "MapFields")]
+pub struct GameMap {
+    width: u32,
+    height: u32,
+    // Row-major wall flags, starting from the bottom row
+    walls: Vec<bool>,
+}
+
+// A map as it comes off the wire, before its size is checked
+#[derive(Deserialize)]
+struct MapFields {
+    width: u32,
+    height: u32,
+    walls: Vec<bool>,
+}
+
+impl TryFrom<MapFields> for GameMap {
+    type Error = String;
+
+    fn try_from(fields: MapFields) -> Result<Self, String> {
+        Self::new(fields.width, fields.height, fields.walls)
+    }
+}
+
+// Default arena: '#' is a wall, '.' is floor, and the first line is the top row
+pub const DEFAULT_MAP: &str = "\
+...............
+...............
+...##.....##...
+...#.......#...
+.......#.......
+.....#####.....
+.......#.......
+...#.......#...
+...##.....##...
+...............
+...............";
+
+impl GameMap {
+    pub fn new(width: u32, height: u32, walls: Vec<bool>) -> Result<Self, String> {
+        if walls.len() as u64 != width as u64 * height as u64 {
+            return Err(format!("map has {} cells, expected {}x{}", walls.len(), width, height));
+        }
+        Ok(Self { width, height, walls })
+    }
+
+    // Parse a map from rows of '#' (wall) and '.' (floor), top row first
+    pub fn from_ascii(rows: &str) -> Result<Self, String> {
+        let lines: Vec<&str> = rows.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+        let width = lines.first().map(|l| l.len()).unwrap_or(0);
+        if width == 0 {
+            return Err("map is empty".to_string());
+        }
+
+        let mut walls = Vec::with_capacity(width * lines.len());
+        for (row, line) in lines.iter().rev().enumerate() {
+            if line.len() != width {
+                return Err(format!("map row {} has length {}, expected {}", row, line.len(), width));
+            }
+            for cell in line.chars() {
+                match cell {
+                    '#' => walls.push(true),
+                    '.' => walls.push(false),
+                    other => return Err(format!("unknown map cell '{}'", other)),
+                }
+            }
+        }
+
+        Self::new(width as u32, lines.len() as u32, walls)
+    }
+
+    // Check that a position is inside the map bounds and not a wall
+    pub fn is_walkable(&self, pos: GridPos) -> bool {
+        if pos.x < 0 || pos.y < 0 {
+            return false;
+        }
+        let (x, y) = (pos.x as u32, pos.y as u32);
+        x < self.width && y < self.height && !self.walls[y as usize * self.width as usize + x as usize]
+    }
+
+    // Hash identifying this map, committed in the journal
+    pub fn hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.width.to_le_bytes());
+        hasher.update(self.height.to_le_bytes());
+        for wall in &self.walls {
+            hasher.update([*wall as u8]);
+        }
+        hasher.finalize().into()
+    }
+}
+
+impl Default for GameMap {
+    fn default() -> Self {
+        Self::from_ascii(DEFAULT_MAP).expect("default map is valid")
+    }
+}
+
+// Hash a position together with the player's secret salt, so consecutive
+// proofs can be chained without revealing where the player actually is
+pub fn position_commitment(position: GridPos, salt: &[u8; SALT_LEN]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(position.x.to_le_bytes());
+    hasher.update(position.y.to_le_bytes());
+    hasher.finalize().into()
 }
@@ -14,35 +14,39 @@
 
 use risc0_zkvm::guest::env;
 
-use footsteps_core::Outputs;
+use footsteps_core::{
+    position_commitment, reveal_seed, reveal_window, GameMap, GameParams, GridPos, Outputs, RevealBeacon,
+    SALT_LEN,
+};
 use bevy_ecs::{prelude::*, world::World};
 use serde::{Deserialize, Serialize};
 
 #[derive(Component, Clone, Copy)]
-struct Position {
-    x: f32,
-    y: f32,
-}
+struct Position(GridPos);
 
 #[derive(Component, Clone, Copy)]
 struct Velocity {
-    x: f32,
-    y: f32,
+    x: i32,
+    y: i32,
 }
 
 
+// The map every move is checked against
+#[derive(Resource)]
+struct Map(GameMap);
+
 #[derive(StageLabel)]
 pub struct UpdateLabel;
 
 // This system moves each entity with a Position and Velocity component
 // Modified to ensure movement is exactly 1 block at a time
-fn movement(mut param_set: ParamSet<(
+fn movement(map: Res<Map>, mut param_set: ParamSet<(
     Query<(&mut Position, &Velocity)>,
 )>) {
     // Then process movement
     for (mut position, velocity) in &mut param_set.p0() {
         // Check for constraint violation (movement must be exactly 1 block)
-        if velocity.x.abs() > 1.1 || velocity.y.abs() > 1.1 {
+        if velocity.x.abs() > 1 || velocity.y.abs() > 1 {
             // This will cause the proof to fail
             // env::log(&format!("CONSTRAINT VIOLATION: Movement must be exactly 1 block at a time. Attempted: ({}, {})", velocity.x, velocity.y));
             // Use panic! instead of env::fail() to abort execution
@@ -50,17 +54,16 @@ fn movement(mut param_set: ParamSet<(
         }
         
         // Normalize movement to exactly 1 block
-        if velocity.x != 0.0 || velocity.y != 0.0 {
-            // Get direction
-            let dx = if velocity.x > 0.0 { 1.0 } else if velocity.x < 0.0 { -1.0 } else { 0.0 };
-            let dy = if velocity.y > 0.0 { 1.0 } else if velocity.y < 0.0 { -1.0 } else { 0.0 };
-            
+        if velocity.x != 0 || velocity.y != 0 {
             // Calculate new position
-            let new_x = position.x + dx;
-            let new_y = position.y + dy;
+            let new_position = position.0.offset(velocity.x.signum(), velocity.y.signum());
+
+            // Moving off the map or into a wall also fails the proof
+            if !map.0.is_walkable(new_position) {
+                panic!("CONSTRAINT VIOLATION: Cannot move into a wall or off the map");
+            }
             
-            position.x = new_x;
-            position.y = new_y;
+            position.0 = new_position;
         }
     }
 }
@@ -83,13 +86,40 @@ fn main() {
     let key_inputs: Vec<KeyInput> = env::read();
     
     // Read the current position from the host
-    let (start_x, start_y): (f32, f32) = env::read();
+    let start: GridPos = env::read();
+
+    // Read the player's secret salt used for the position commitments
+    let salt: [u8; SALT_LEN] = env::read();
+
+    // Read the map the movement is played on
+    let map: GameMap = env::read();
+    if !map.is_walkable(start) {
+        panic!("CONSTRAINT VIOLATION: Starting position is not on the map");
+    }
+    let map_hash = map.hash();
+
+    // Read the game rules, then the previous journal digest and the other
+    // player's batch the reveal window is seeded from
+    let params: GameParams = env::read();
+    let previous_digest: [u8; 32] = env::read();
+    let reveal_beacon: Option<RevealBeacon> = env::read();
+    let reveal_seed = reveal_seed(&previous_digest, reveal_beacon.as_ref());
+
+    // Read the epoch and batch sequence number, committed so verifiers can drop replays
+    let epoch: u64 = env::read();
+    let sequence: u64 = env::read();
+
+    // Read who is proving and the match they're playing, committed so the
+    // receipt can't be claimed by anyone else or replayed into another lobby
+    let player_id: String = env::read();
+    let match_id: [u8; 32] = env::read();
     
     let mut world = World::new();
+    world.insert_resource(Map(map));
     
     // Spawn player
     let entity = world
-        .spawn((Position { x: start_x, y: start_y }, Velocity { x: 0.0, y: 0.0 }))
+        .spawn((Position(start), Velocity { x: 0, y: 0 }))
         .id();
 
     let mut schedule = Schedule::default();
@@ -104,7 +134,7 @@ fn main() {
     let mut all_positions = Vec::with_capacity(key_inputs.len() + 1);
     
     // Add starting position
-    all_positions.push(Position { x: start_x, y: start_y });
+    all_positions.push(start);
     
     // Process each key input
     for key in key_inputs {
@@ -114,18 +144,18 @@ fn main() {
             let mut velocity = entity_mut.get_mut::<Velocity>().unwrap();
             
             // Reset velocity
-            velocity.x = 0.0;
-            velocity.y = 0.0;
+            velocity.x = 0;
+            velocity.y = 0;
             
             // Set velocity based on key input
             match key {
-                KeyInput::Up => velocity.y = 1.0,
-                KeyInput::Down => velocity.y = -1.0,
-                KeyInput::Left => velocity.x = -1.0,
-                KeyInput::Right => velocity.x = 1.0,
+                KeyInput::Up => velocity.y = 1,
+                KeyInput::Down => velocity.y = -1,
+                KeyInput::Left => velocity.x = -1,
+                KeyInput::Right => velocity.x = 1,
                 KeyInput::TestConstraint => {
                     // Try to move by 3 units (should violate constraints)
-                    velocity.x = 3.0;
+                    velocity.x = 3;
                     env::log("Attempting to move by 3 units (should violate constraints and cause panic)");
                     env::log("This will trigger the constraint check in the movement system");
                 },
@@ -142,37 +172,37 @@ fn main() {
         
         // Add current position to all positions if we moved
         let velocity = entity_ref.get::<Velocity>().unwrap();
-        if velocity.x != 0.0 || velocity.y != 0.0 {
-            all_positions.push((*position).clone());
+        if velocity.x != 0 || velocity.y != 0 {
+            all_positions.push(position.0);
         }
     }
     
-    // Select the middle sequence of the trail
-    let trail_positions = if all_positions.len() <= 1 {
-        // If there's only the starting position or no movement, return empty trail
-        Vec::new()
-    } else if all_positions.len() <= 4 {
-        // If there are 2-4 positions (including start), return all except the last one
-        all_positions[0..all_positions.len()-1].iter().map(|p| (p.x, p.y)).collect()
-    } else {
-        // For longer trails, select the middle 50% of the trail
-
-        let middle_index = all_positions.len() / 2;
-        let start_index = middle_index / 2;
-        let end_index = middle_index + start_index;
-        
-        // Ensure we don't go out of bounds
-        let start_index = start_index.max(0);
-        let end_index = end_index.min(all_positions.len() - 1);
-        
-        // Extract the middle sequence
-        all_positions[start_index..end_index].iter().map(|p| (p.x, p.y)).collect()
-    };
+    // Commit to where this batch started and ended so the next batch can be chained to it
+    let end_position = *all_positions.last().unwrap();
+    let start_commitment = position_commitment(start, &salt);
+    let end_commitment = position_commitment(end_position, &salt);
+
+    // Select the window of the trail to reveal, never including the final position
+    let candidates = &all_positions[..all_positions.len() - 1];
+    let window = reveal_window(&reveal_seed, candidates.len(), &params);
+    let reveal_indices: Vec<u32> = window.clone().map(|i| i as u32).collect();
+    let trail_positions = candidates[window].to_vec();
     
     // Output only the selected trail, not the final position
     {
         let out = Outputs {
             trail_positions,
+            start_commitment,
+            end_commitment,
+            map_hash,
+            params,
+            reveal_seed,
+            reveal_beacon,
+            reveal_indices,
+            epoch,
+            sequence,
+            player_id,
+            match_id,
         };
         env::commit(&out);
     }
@@ -111,7 +111,7 @@ fi
 
 # Start the Rust server with the ngrok URL
 echo "Starting Rust server..."
-RISC0_PROVER=local ./target/release/footsteps "$NODE_NAME" "$WS_PORT" "$P2P_PORT" "$THEIR_P2P_ADDRESS" "$NGROK_URL" &
+RISC0_PROVER=local ./target/release/footsteps --name "$NODE_NAME" --ws-port "$WS_PORT" --p2p-port "$P2P_PORT" --peer "$THEIR_P2P_ADDRESS" --custom-url "$NGROK_URL" &
 RUST_PID=$!
 
 # Wait for the Rust server to exit
@@ -0,0 +1,96 @@
+// Our position chain across restarts.
+//
+// Every batch must start where our previous batch ended, under the same salt,
+// and be seeded from the previous journal. Peers hold a restarted node to
+// that too, so where the chain left off is saved next to the key file and
+// each run continues it under a higher epoch.
+use footsteps_core::{GridPos, SALT_LEN};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChainState {
+    pub salt: [u8; SALT_LEN],     // Secret salt for our position commitments
+    pub epoch: u64,               // Epoch of the run that saved it
+    pub end: GridPos,             // Where our last published batch ended
+    pub journal_digest: [u8; 32], // Digest of our last published journal
+}
+
+impl ChainState {
+    // A chain nobody has seen yet
+    pub fn new() -> Self {
+        Self {
+            salt: *uuid::Uuid::new_v4().as_bytes(),
+            epoch: 0,
+            end: GridPos::default(),
+            journal_digest: [0; 32],
+        }
+    }
+
+    // Chain file kept alongside a key file
+    pub fn path(key_file: &Path) -> PathBuf {
+        key_file.with_extension("chain")
+    }
+
+    // Continue the saved chain under the next epoch, or start one. The new
+    // epoch is saved right away so a run that publishes nothing still uses it up.
+    pub fn resume(path: &Path) -> Result<Self, String> {
+        let chain = if path.exists() {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read chain file {}: {}", path.display(), e))?;
+            let saved: Self = serde_json::from_str(&contents)
+                .map_err(|e| format!("Invalid chain file {}: {}", path.display(), e))?;
+            println!("Continuing chain from {} at ({}, {})", path.display(), saved.end.x, saved.end.y);
+            Self { epoch: saved.epoch + 1, ..saved }
+        } else {
+            println!("Starting a new chain, saved to {}", path.display());
+            Self::new()
+        };
+        chain.save(path)?;
+        Ok(chain)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        // Write a temporary file and rename it, so a crash can't leave half a chain
+        let temp = path.with_extension("chain.tmp");
+        fs::write(&temp, contents)
+            .and_then(|_| restrict_permissions(&temp))
+            .and_then(|_| fs::rename(&temp, path))
+            .map_err(|e| format!("Failed to save chain file {}: {}", path.display(), e))
+    }
+}
+
+// The salt is as secret as the key file
+fn restrict_permissions(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes_under_the_next_epoch() {
+        let path = std::env::temp_dir().join(format!("footsteps-{}.chain", uuid::Uuid::new_v4()));
+        let first = ChainState::resume(&path).unwrap();
+        assert_eq!(first.epoch, 0);
+
+        let published = ChainState { end: GridPos::new(3, 4), journal_digest: [7; 32], ..first.clone() };
+        published.save(&path).unwrap();
+
+        let second = ChainState::resume(&path).unwrap();
+        assert_eq!(second, ChainState { epoch: 1, ..published });
+        // A run that publishes nothing still moves the epoch on
+        assert_eq!(ChainState::resume(&path).unwrap().epoch, 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+// Chunked transfer for proofs too large for a single gossipsub message.
+//
+// The sender splits an encoded P2PMessage::Proof into numbered ProofChunks
+// that all carry the SHA-256 of the whole payload. Receivers hold chunks
+// until every one has arrived and the reassembled payload matches the hash.
+// Transfers that stall, grow too large or exceed the buffer budget are dropped.
+use crate::p2p::P2PMessage;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Bytes of payload per chunk, leaving room for the envelope under the transmit limit
+pub const CHUNK_SIZE: usize = 512 * 1024;
+
+// Largest payload accepted in chunks
+const MAX_TRANSFER_LEN: usize = 64 * 1024 * 1024;
+
+// Bytes buffered across all unfinished transfers
+const MAX_BUFFERED: usize = 128 * 1024 * 1024;
+
+// Unfinished transfers a single player may have at once
+const MAX_TRANSFERS_PER_PLAYER: usize = 2;
+
+// Split an encoded message into chunks that reassemble to it
+pub fn split(player_id: &str, data: &[u8]) -> Vec<P2PMessage> {
+    let content_hash: [u8; 32] = Sha256::digest(data).into();
+    let total = data.len().div_ceil(CHUNK_SIZE) as u32;
+    data.chunks(CHUNK_SIZE)
+        .enumerate()
+        .map(|(index, chunk)| P2PMessage::ProofChunk {
+            player_id: player_id.to_string(),
+            content_hash,
+            index: index as u32,
+            total,
+            data: chunk.to_vec(),
+        })
+        .collect()
+}
+
+// Why an unfinished transfer was abandoned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkFailure {
+    TimedOut,
+    MemoryLimit,
+    TooManyTransfers,
+    HashMismatch,
+}
+
+impl std::fmt::Display for ChunkFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkFailure::TimedOut => write!(f, "timed out"),
+            ChunkFailure::MemoryLimit => write!(f, "reassembly buffer full"),
+            ChunkFailure::TooManyTransfers => write!(f, "too many transfers in progress"),
+            ChunkFailure::HashMismatch => write!(f, "content hash does not match"),
+        }
+    }
+}
+
+// What happened to a received chunk. `T` identifies the gossip message each
+// chunk arrived in, so the caller can settle them once the transfer ends.
+#[derive(Debug)]
+pub enum ChunkEvent<T> {
+    // Chunk is held until the rest of the transfer arrives
+    Partial { received: u32, total: u32 },
+    // Transfer reassembled and matched its hash; `held` includes this chunk
+    Complete { data: Vec<u8>, held: Vec<T> },
+    // Transfer abandoned; `held` includes this chunk
+    Failed { reason: ChunkFailure, held: Vec<T> },
+    // Chunk was already received
+    Duplicate,
+    // Chunk is malformed or inconsistent with its transfer
+    Invalid(String),
+}
+
+struct Transfer<T> {
+    total: u32,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    bytes: usize,
+    started: Instant,
+    held: Vec<T>,
+}
+
+// Reassembles chunked transfers, keyed by sender and content hash
+pub struct Reassembler<T> {
+    timeout: Duration,
+    transfers: HashMap<(String, [u8; 32]), Transfer<T>>,
+    buffered: usize,
+}
+
+impl<T> Reassembler<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            transfers: HashMap::new(),
+            buffered: 0,
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        player_id: &str,
+        content_hash: [u8; 32],
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+        tag: T,
+    ) -> ChunkEvent<T> {
+        if total == 0 || index >= total {
+            return ChunkEvent::Invalid(format!("chunk {} of {}", index, total));
+        }
+        if total as usize > MAX_TRANSFER_LEN.div_ceil(CHUNK_SIZE) {
+            return ChunkEvent::Invalid(format!("transfer of {} chunks is too large", total));
+        }
+        if data.is_empty() || data.len() > CHUNK_SIZE {
+            return ChunkEvent::Invalid(format!("chunk of {} bytes", data.len()));
+        }
+
+        let key = (player_id.to_string(), content_hash);
+        if !self.transfers.contains_key(&key) {
+            let in_progress = self.transfers.keys().filter(|(player, _)| player == player_id).count();
+            if in_progress >= MAX_TRANSFERS_PER_PLAYER {
+                return ChunkEvent::Failed { reason: ChunkFailure::TooManyTransfers, held: vec![tag] };
+            }
+            self.transfers.insert(
+                key.clone(),
+                Transfer {
+                    total,
+                    chunks: vec![None; total as usize],
+                    received: 0,
+                    bytes: 0,
+                    started: Instant::now(),
+                    held: Vec::new(),
+                },
+            );
+        }
+
+        let transfer = self.transfers.get_mut(&key).unwrap();
+        if transfer.total != total {
+            return ChunkEvent::Invalid(format!("chunk claims {} chunks, transfer has {}", total, transfer.total));
+        }
+        if transfer.chunks[index as usize].is_some() {
+            return ChunkEvent::Duplicate;
+        }
+        if self.buffered + data.len() > MAX_BUFFERED {
+            let mut held = self.remove(&key);
+            held.push(tag);
+            return ChunkEvent::Failed { reason: ChunkFailure::MemoryLimit, held };
+        }
+
+        self.buffered += data.len();
+        transfer.bytes += data.len();
+        transfer.chunks[index as usize] = Some(data);
+        transfer.received += 1;
+        transfer.held.push(tag);
+        if transfer.received < transfer.total {
+            return ChunkEvent::Partial { received: transfer.received, total: transfer.total };
+        }
+
+        let transfer = self.transfers.remove(&key).unwrap();
+        self.buffered -= transfer.bytes;
+        let data: Vec<u8> = transfer.chunks.into_iter().flatten().flatten().collect();
+        if <[u8; 32]>::from(Sha256::digest(&data)) != content_hash {
+            return ChunkEvent::Failed { reason: ChunkFailure::HashMismatch, held: transfer.held };
+        }
+        ChunkEvent::Complete { data, held: transfer.held }
+    }
+
+    // Drop transfers that have been waiting longer than the timeout.
+    // Returns the sender and held chunks of each.
+    pub fn expire(&mut self) -> Vec<(String, Vec<T>)> {
+        let expired: Vec<_> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.started.elapsed() >= self.timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired
+            .into_iter()
+            .map(|key| {
+                let held = self.remove(&key);
+                (key.0, held)
+            })
+            .collect()
+    }
+
+    fn remove(&mut self, key: &(String, [u8; 32])) -> Vec<T> {
+        match self.transfers.remove(key) {
+            Some(transfer) => {
+                self.buffered -= transfer.bytes;
+                transfer.held
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_fields(msg: P2PMessage) -> ([u8; 32], u32, u32, Vec<u8>) {
+        match msg {
+            P2PMessage::ProofChunk { content_hash, index, total, data, .. } => (content_hash, index, total, data),
+            other => panic!("expected a chunk, got {:?}", other),
+        }
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn reassembles_out_of_order_chunks() {
+        let data = payload(CHUNK_SIZE * 2 + 10);
+        let mut chunks = split("alice", &data);
+        assert_eq!(chunks.len(), 3);
+        chunks.reverse();
+
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let mut events = Vec::new();
+        for (tag, chunk) in chunks.into_iter().enumerate() {
+            let (hash, index, total, bytes) = chunk_fields(chunk);
+            events.push(reassembler.add("alice", hash, index, total, bytes, tag));
+        }
+
+        assert!(matches!(events[0], ChunkEvent::Partial { received: 1, total: 3 }));
+        match events.pop().unwrap() {
+            ChunkEvent::Complete { data: reassembled, held } => {
+                assert_eq!(reassembled, data);
+                assert_eq!(held, vec![0, 1, 2]);
+            }
+            other => panic!("expected completion, got {:?}", other),
+        }
+        assert_eq!(reassembler.buffered, 0);
+    }
+
+    #[test]
+    fn fails_on_hash_mismatch() {
+        let data = payload(CHUNK_SIZE + 1);
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        let mut last = None;
+        for (tag, chunk) in split("alice", &data).into_iter().enumerate() {
+            let (hash, index, total, mut bytes) = chunk_fields(chunk);
+            bytes[0] ^= 1;
+            last = Some(reassembler.add("alice", hash, index, total, bytes, tag));
+        }
+        assert!(matches!(last, Some(ChunkEvent::Failed { reason: ChunkFailure::HashMismatch, .. })));
+    }
+
+    #[test]
+    fn rejects_malformed_and_duplicate_chunks() {
+        let mut reassembler = Reassembler::new(Duration::from_secs(60));
+        assert!(matches!(reassembler.add("alice", [0; 32], 2, 2, vec![1], 0), ChunkEvent::Invalid(_)));
+        assert!(matches!(reassembler.add("alice", [0; 32], 0, u32::MAX, vec![1], 0), ChunkEvent::Invalid(_)));
+        assert!(matches!(reassembler.add("alice", [0; 32], 0, 2, vec![1], 0), ChunkEvent::Partial { .. }));
+        assert!(matches!(reassembler.add("alice", [0; 32], 0, 2, vec![1], 1), ChunkEvent::Duplicate));
+        assert!(matches!(reassembler.add("alice", [0; 32], 1, 3, vec![1], 2), ChunkEvent::Invalid(_)));
+    }
+
+    #[test]
+    fn limits_transfers_per_player_and_expires_stalled_ones() {
+        let mut reassembler = Reassembler::new(Duration::ZERO);
+        for hash in 0..MAX_TRANSFERS_PER_PLAYER as u8 {
+            assert!(matches!(reassembler.add("alice", [hash; 32], 0, 2, vec![1], hash), ChunkEvent::Partial { .. }));
+        }
+        assert!(matches!(
+            reassembler.add("alice", [0xff; 32], 0, 2, vec![1], 0xff),
+            ChunkEvent::Failed { reason: ChunkFailure::TooManyTransfers, .. }
+        ));
+
+        let expired = reassembler.expire();
+        assert_eq!(expired.len(), MAX_TRANSFERS_PER_PLAYER);
+        assert!(reassembler.transfers.is_empty());
+        assert_eq!(reassembler.buffered, 0);
+    }
+}
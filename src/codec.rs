@@ -0,0 +1,249 @@
+// Wire format for P2PMessage.
+//
+// Every message is a small header followed by a bincode payload:
+//
+//   magic (4 bytes) | version (1 byte) | flags (1 byte) | payload
+//
+// When the `FLAG_DEFLATE` flag is set the payload is deflate-compressed.
+// Messages without the magic header are decoded as legacy JSON so older
+// nodes can still be understood while everyone upgrades.
+use crate::p2p::P2PMessage;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{Read, Write};
+
+pub const MAGIC: &[u8; 4] = b"FTSP";
+pub const VERSION: u8 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+// Payload is deflate-compressed
+const FLAG_DEFLATE: u8 = 0b0000_0001;
+
+// Payloads smaller than this are sent as-is; compressing them isn't worth it
+const COMPRESSION_THRESHOLD: usize = 512;
+
+// Upper bound on a decompressed payload, so a tiny message can't expand without limit
+const MAX_DECODED_LEN: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    // Header has our magic but a version we don't understand
+    UnsupportedVersion(u8),
+    // Header has flags we don't understand
+    UnsupportedFlags(u8),
+    // Payload could not be (de)compressed or (de)serialized
+    Payload(String),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::UnsupportedVersion(v) => write!(f, "unsupported wire version {}", v),
+            CodecError::UnsupportedFlags(flags) => write!(f, "unsupported wire flags {:#010b}", flags),
+            CodecError::Payload(e) => write!(f, "invalid payload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+// Encode a message, compressing the payload when that makes it smaller
+pub fn encode(msg: &P2PMessage) -> Result<Vec<u8>, CodecError> {
+    let payload = bincode::serialize(msg).map_err(|e| CodecError::Payload(e.to_string()))?;
+
+    let (flags, payload) = if payload.len() >= COMPRESSION_THRESHOLD {
+        let compressed = deflate(&payload)?;
+        if compressed.len() < payload.len() {
+            (FLAG_DEFLATE, compressed)
+        } else {
+            (0, payload)
+        }
+    } else {
+        (0, payload)
+    };
+
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.push(flags);
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+// Decode a message in either the binary envelope or the legacy JSON format
+pub fn decode(data: &[u8]) -> Result<P2PMessage, CodecError> {
+    if !data.starts_with(MAGIC) {
+        return serde_json::from_slice(data).map_err(|e| CodecError::Payload(e.to_string()));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(CodecError::Payload("truncated header".to_string()));
+    }
+
+    let version = data[MAGIC.len()];
+    let flags = data[MAGIC.len() + 1];
+    if version != VERSION {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+    if flags & !FLAG_DEFLATE != 0 {
+        return Err(CodecError::UnsupportedFlags(flags));
+    }
+
+    let payload = &data[HEADER_LEN..];
+    let result = if flags & FLAG_DEFLATE != 0 {
+        bincode::deserialize(&inflate(payload)?)
+    } else {
+        bincode::deserialize(payload)
+    };
+    result.map_err(|e| CodecError::Payload(e.to_string()))
+}
+
+fn deflate(payload: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(payload)
+        .and_then(|_| encoder.finish())
+        .map_err(|e| CodecError::Payload(e.to_string()))
+}
+
+fn inflate(payload: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let mut decoded = Vec::new();
+    DeflateDecoder::new(payload)
+        .take(MAX_DECODED_LEN + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|e| CodecError::Payload(e.to_string()))?;
+    if decoded.len() as u64 > MAX_DECODED_LEN {
+        return Err(CodecError::Payload("decompressed payload too large".to_string()));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use footsteps_methods::FOOTSTEPS_GUEST_ID;
+    use risc0_zkvm::{FakeReceipt, InnerReceipt, Receipt, ReceiptClaim};
+
+    pub(crate) fn fake_receipt() -> Receipt {
+        Receipt::new(
+            InnerReceipt::Fake(FakeReceipt::new(ReceiptClaim::ok(FOOTSTEPS_GUEST_ID, Vec::new()))),
+            Vec::new(),
+        )
+    }
+
+    fn all_variants() -> Vec<P2PMessage> {
+        vec![
+            P2PMessage::Proof {
+                player_id: "12D3KooWProver".to_string(),
+                receipt: fake_receipt(),
+                ImageID: FOOTSTEPS_GUEST_ID,
+            },
+            P2PMessage::ProofAnnouncement {
+                player_id: "12D3KooWProver".to_string(),
+                epoch: 2,
+                sequence: 7,
+                journal_digest: [1; 32],
+                receipt_hash: [2; 32],
+                ImageID: FOOTSTEPS_GUEST_ID,
+            },
+            P2PMessage::PlayerJoined {
+                player_id: "12D3KooWJoined".to_string(),
+                name: "alice".to_string(),
+            },
+            P2PMessage::PlayerLeft {
+                player_id: "12D3KooWLeft".to_string(),
+            },
+            P2PMessage::NodeInfo {
+                node_id: "12D3KooWNode".to_string(),
+                name: "bob".to_string(),
+                custom_url: "https://example.com/verify".to_string(),
+            },
+            P2PMessage::ProofChunk {
+                player_id: "12D3KooWProver".to_string(),
+                content_hash: [3; 32],
+                index: 1,
+                total: 4,
+                data: vec![0xab; 64],
+            },
+            P2PMessage::Heartbeat {
+                player_id: "12D3KooWAlive".to_string(),
+            },
+            P2PMessage::LobbyAdvert {
+                node_id: "12D3KooWNode".to_string(),
+                lobby: Some("friday".to_string()),
+                rules: footsteps_core::GameParams::default(),
+                map_hash: [4; 32],
+            },
+            P2PMessage::BatchCut {
+                player_id: "12D3KooWProver".to_string(),
+                epoch: 2,
+                sequence: 8,
+                beacon: Some(footsteps_core::RevealBeacon {
+                    player_id: "12D3KooWOther".to_string(),
+                    epoch: 1,
+                    sequence: 3,
+                    journal_digest: [5; 32],
+                }),
+            },
+        ]
+    }
+
+    // P2PMessage doesn't implement PartialEq (Receipt doesn't), so compare the JSON forms
+    fn assert_same(a: &P2PMessage, b: &P2PMessage) {
+        assert_eq!(serde_json::to_value(a).unwrap(), serde_json::to_value(b).unwrap());
+    }
+
+    #[test]
+    fn round_trips_every_variant() {
+        for msg in all_variants() {
+            let data = encode(&msg).unwrap();
+            assert!(data.starts_with(MAGIC));
+            assert_eq!(data[MAGIC.len()], VERSION);
+            assert_same(&decode(&data).unwrap(), &msg);
+        }
+    }
+
+    #[test]
+    fn decodes_legacy_json_for_every_variant() {
+        for msg in all_variants() {
+            let data = serde_json::to_vec(&msg).unwrap();
+            assert_same(&decode(&data).unwrap(), &msg);
+        }
+    }
+
+    #[test]
+    fn compresses_large_payloads() {
+        let msg = P2PMessage::NodeInfo {
+            node_id: "12D3KooWNode".to_string(),
+            name: "x".repeat(4096),
+            custom_url: String::new(),
+        };
+        let data = encode(&msg).unwrap();
+        assert_eq!(data[MAGIC.len() + 1], FLAG_DEFLATE);
+        assert!(data.len() < 4096);
+        assert_same(&decode(&data).unwrap(), &msg);
+    }
+
+    #[test]
+    fn binary_is_smaller_than_json() {
+        for msg in all_variants() {
+            assert!(encode(&msg).unwrap().len() < serde_json::to_vec(&msg).unwrap().len());
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_versions_and_flags() {
+        let mut data = encode(&all_variants()[2]).unwrap();
+        data[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(decode(&data), Err(CodecError::UnsupportedVersion(_))));
+
+        let mut data = encode(&all_variants()[2]).unwrap();
+        data[MAGIC.len() + 1] = 0b1000_0000;
+        assert!(matches!(decode(&data), Err(CodecError::UnsupportedFlags(_))));
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        assert!(decode(&MAGIC[..]).is_err());
+        let data = encode(&all_variants()[4]).unwrap();
+        assert!(decode(&data[..data.len() - 3]).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+// Node configuration.
+//
+// Settings are layered, each overriding the one before: built-in defaults, a
+// TOML file given with --config, FOOTSTEPS_* environment variables and finally
+// command-line flags. `--print-config` shows the result as TOML, which can be
+// saved and used as a config file.
+use crate::{lobby, pipeline, verifier};
+use clap::Parser;
+use footsteps_core::GameParams;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub name: String,            // Shown to other players
+    pub ws_bind: IpAddr,         // Address the WebSocket server for the frontend binds to
+    pub ws_port: u16,
+    pub p2p_bind: IpAddr,        // Address the P2P node listens on, over TCP and QUIC
+    pub p2p_port: u16,
+    pub peers: Vec<String>,      // Known peers to dial: multiaddrs, or host:port for TCP
+    pub bootstrap: Vec<String>,  // DHT bootstrap multiaddrs, each ending in /p2p/<peer id>
+    pub mdns: bool,              // Discover peers on the local network
+    pub custom_url: String,      // URL shared with other nodes
+    pub key_file: PathBuf,       // libp2p identity; empty means <name>.key
+    pub lobby: String,           // Lobby to join at startup; empty joins none
+    pub reveal_percent: u8,      // Share of each batch revealed; every peer must agree
+    pub batch_interval_ms: u64,
+    pub max_batch_size: usize,
+    pub max_in_flight: usize,    // Batches proven at once
+    pub idle_flush_ms: u64,      // Cut a batch once input pauses this long; 0 disables
+    pub prover: String,          // local, dev or remote:<host:port>
+    pub prover_listen: String,   // Serve proofs for other nodes on this trusted address; empty disables
+    pub receipt_kind: String,    // composite, succinct or groth16
+    pub proof_distribution: String, // announce or broadcast
+    pub verifier_workers: usize,
+    pub max_queued_proofs: usize,
+    pub accept_dev_receipts: bool, // Accept other players' fake receipts
+    pub extra_image_ids: Vec<String>, // Other guest builds whose proofs are accepted, as hex
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let pipeline = pipeline::PipelineConfig::default();
+        let verifier = verifier::VerifierConfig::default();
+        Self {
+            name: "node".to_string(),
+            ws_bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            ws_port: 3001,
+            p2p_bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            p2p_port: 9000,
+            peers: Vec::new(),
+            bootstrap: Vec::new(),
+            mdns: true,
+            custom_url: String::new(),
+            key_file: PathBuf::new(),
+            lobby: lobby::DEFAULT_LOBBY.to_string(),
+            reveal_percent: GameParams::default().reveal_percent,
+            batch_interval_ms: pipeline.batch_interval.as_millis() as u64,
+            max_batch_size: pipeline.max_batch_size,
+            max_in_flight: pipeline.max_in_flight,
+            idle_flush_ms: pipeline.idle_flush.map_or(0, |idle| idle.as_millis() as u64),
+            prover: "local".to_string(),
+            prover_listen: String::new(),
+            receipt_kind: "succinct".to_string(),
+            proof_distribution: "announce".to_string(),
+            verifier_workers: verifier.workers,
+            max_queued_proofs: verifier.max_queued,
+            accept_dev_receipts: verifier.accept_dev_receipts,
+            extra_image_ids: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    // Defaults, then the config file, then environment variables and flags
+    pub fn load(cli: Cli) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        cli.apply(&mut config);
+        if config.key_file.as_os_str().is_empty() {
+            config.key_file = format!("{}.key", config.name).into();
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        Self::from_toml(&contents).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    pub fn to_toml(&self) -> String {
+        // Every field is a plain value or a list of them, so this can't fail
+        toml::to_string(self).expect("config serializes to TOML")
+    }
+}
+
+// Command-line flags. Each setting can also be given as an environment
+// variable; both override the config file.
+#[derive(Debug, Parser)]
+#[command(name = "footsteps", version, about = "Footsteps node: proves your moves and verifies everyone else's")]
+pub struct Cli {
+    /// TOML config file
+    #[arg(long, short, env = "FOOTSTEPS_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Print the resulting configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Name shown to other players
+    #[arg(long, env = "FOOTSTEPS_NAME")]
+    name: Option<String>,
+
+    /// Address the WebSocket server binds to
+    #[arg(long, env = "FOOTSTEPS_WS_BIND")]
+    ws_bind: Option<IpAddr>,
+
+    /// WebSocket port for the frontend
+    #[arg(long, env = "FOOTSTEPS_WS_PORT")]
+    ws_port: Option<u16>,
+
+    /// Address the P2P node listens on
+    #[arg(long, env = "FOOTSTEPS_P2P_BIND")]
+    p2p_bind: Option<IpAddr>,
+
+    /// P2P port, used for both TCP and QUIC
+    #[arg(long, env = "FOOTSTEPS_P2P_PORT")]
+    p2p_port: Option<u16>,
+
+    /// Known peer to dial: a multiaddr, or host:port for TCP (repeatable or comma-separated)
+    #[arg(long = "peer", env = "FOOTSTEPS_PEERS", value_delimiter = ',')]
+    peers: Option<Vec<String>>,
+
+    /// DHT bootstrap multiaddr ending in /p2p/<peer id> (repeatable or comma-separated)
+    #[arg(long, env = "FOOTSTEPS_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap: Option<Vec<String>>,
+
+    /// Discover peers on the local network
+    #[arg(long, env = "FOOTSTEPS_MDNS", value_name = "on|off", value_parser = clap::builder::BoolishValueParser::new())]
+    mdns: Option<bool>,
+
+    /// URL shared with other nodes
+    #[arg(long, env = "FOOTSTEPS_CUSTOM_URL")]
+    custom_url: Option<String>,
+
+    /// File holding this node's libp2p identity, created on first run [default: <name>.key]
+    #[arg(long, env = "FOOTSTEPS_KEY_FILE")]
+    key_file: Option<PathBuf>,
+
+    /// Lobby to join at startup; empty joins none
+    #[arg(long, env = "FOOTSTEPS_LOBBY")]
+    lobby: Option<String>,
+
+    /// Percentage of each batch revealed to other players; every peer must use the same value
+    #[arg(long, env = "FOOTSTEPS_REVEAL_PERCENT")]
+    reveal_percent: Option<u8>,
+
+    /// Longest time key presses wait before they are proven, in milliseconds
+    #[arg(long, env = "FOOTSTEPS_BATCH_INTERVAL_MS")]
+    batch_interval_ms: Option<u64>,
+
+    /// Most key presses proven in one batch
+    #[arg(long, env = "FOOTSTEPS_MAX_BATCH_SIZE")]
+    max_batch_size: Option<usize>,
+
+    /// Batches proven at once
+    #[arg(long, env = "FOOTSTEPS_MAX_IN_FLIGHT")]
+    max_in_flight: Option<usize>,
+
+    /// Cut a batch once input pauses this long, in milliseconds; 0 disables
+    #[arg(long, env = "FOOTSTEPS_IDLE_FLUSH_MS")]
+    idle_flush_ms: Option<u64>,
+
+    /// Proving backend: local, dev (fake receipts, for development only) or remote:<host:port>
+    #[arg(long, env = "FOOTSTEPS_PROVER")]
+    prover: Option<String>,
+
+    /// Serve proofs for other nodes' remote provers on this address. Clients aren't
+    /// authenticated, so only use a trusted interface such as localhost or a private network
+    #[arg(long, env = "FOOTSTEPS_PROVER_LISTEN")]
+    prover_listen: Option<String>,
+
+    /// Kind of receipt to publish: composite, succinct or groth16
+    #[arg(long, env = "FOOTSTEPS_RECEIPT_KIND")]
+    receipt_kind: Option<String>,
+
+    /// How proofs reach other players: announce or broadcast
+    #[arg(long, env = "FOOTSTEPS_PROOF_DISTRIBUTION")]
+    proof_distribution: Option<String>,
+
+    /// Receipts verified concurrently
+    #[arg(long, env = "FOOTSTEPS_VERIFIER_WORKERS")]
+    verifier_workers: Option<usize>,
+
+    /// Received proofs waiting for a verifier before new ones are turned away
+    #[arg(long, env = "FOOTSTEPS_MAX_QUEUED_PROOFS")]
+    max_queued_proofs: Option<usize>,
+
+    /// Accept other players' fake receipts from dev-mode provers
+    #[arg(long, env = "FOOTSTEPS_ACCEPT_DEV_RECEIPTS", value_name = "on|off", value_parser = clap::builder::BoolishValueParser::new())]
+    accept_dev_receipts: Option<bool>,
+
+    /// Other guest image ID, as hex, whose proofs are accepted (repeatable or comma-separated)
+    #[arg(long = "extra-image-id", env = "FOOTSTEPS_EXTRA_IMAGE_IDS", value_delimiter = ',')]
+    extra_image_ids: Option<Vec<String>>,
+}
+
+impl Cli {
+    // Override the settings that were given on the command line or in the environment
+    fn apply(self, config: &mut Config) {
+        macro_rules! apply {
+            ($($field:ident),* $(,)?) => {
+                $(if let Some(value) = self.$field {
+                    config.$field = value;
+                })*
+            };
+        }
+        apply!(
+            name,
+            ws_bind,
+            ws_port,
+            p2p_bind,
+            p2p_port,
+            peers,
+            bootstrap,
+            mdns,
+            custom_url,
+            key_file,
+            lobby,
+            reveal_percent,
+            batch_interval_ms,
+            max_batch_size,
+            max_in_flight,
+            idle_flush_ms,
+            prover,
+            prover_listen,
+            receipt_kind,
+            proof_distribution,
+            verifier_workers,
+            max_queued_proofs,
+            accept_dev_receipts,
+            extra_image_ids,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Tests share the process environment, so they take turns changing it
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    // Load a config the way `footsteps <args>` would, with `env` as the only
+    // FOOTSTEPS_* variables set and `file`, if any, as the config file
+    fn load(args: &[&str], env: &[(&str, &str)], file: Option<&str>) -> Result<Config, String> {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!("footsteps-config-{}.toml", uuid::Uuid::new_v4()));
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        if let Some(contents) = file {
+            std::fs::write(&path, contents).unwrap();
+            args.extend(["--config".to_string(), path.display().to_string()]);
+        }
+
+        let saved: Vec<(String, String)> = std::env::vars().filter(|(key, _)| key.starts_with("FOOTSTEPS_")).collect();
+        for (key, _) in &saved {
+            std::env::remove_var(key);
+        }
+        for (key, value) in env {
+            std::env::set_var(key, value);
+        }
+
+        let result = Cli::try_parse_from(std::iter::once("footsteps".to_string()).chain(args))
+            .map_err(|e| e.to_string())
+            .and_then(Config::load);
+
+        for (key, _) in env {
+            std::env::remove_var(key);
+        }
+        for (key, value) in &saved {
+            std::env::set_var(key, value);
+        }
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn layers_flags_over_env_over_file() {
+        let file = r#"
+            name = "alice"
+            ws_port = 4001
+            p2p_port = 9100
+            peers = ["/dns4/example.com/udp/9000/quic-v1"]
+            mdns = false
+        "#;
+        let env = [
+            ("FOOTSTEPS_WS_PORT", "5001"),
+            ("FOOTSTEPS_P2P_PORT", "9200"),
+            ("FOOTSTEPS_PEERS", "10.0.0.2:9000,10.0.0.3:9000"),
+        ];
+        let config = load(&["--p2p-port", "9300", "--mdns", "on"], &env, Some(file)).unwrap();
+        assert_eq!(config.name, "alice");
+        assert_eq!(config.ws_port, 5001); // Environment over file
+        assert_eq!(config.peers, vec!["10.0.0.2:9000", "10.0.0.3:9000"]);
+        assert_eq!(config.p2p_port, 9300); // Flag over environment
+        assert!(config.mdns); // Flag over file
+        // Settings nobody gave keep their defaults
+        assert_eq!(config.ws_bind, Config::default().ws_bind);
+        assert_eq!(config.lobby, lobby::DEFAULT_LOBBY);
+        assert_eq!(config.key_file, PathBuf::from("alice.key"));
+    }
+
+    #[test]
+    fn finds_the_config_file_through_the_environment() {
+        let path = std::env::temp_dir().join(format!("footsteps-config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "name = \"carol\"\nkey_file = \"keys/carol.key\"").unwrap();
+        let config = load(&[], &[("FOOTSTEPS_CONFIG", &path.display().to_string())], None);
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.name, "carol");
+        assert_eq!(config.key_file, PathBuf::from("keys/carol.key"));
+    }
+
+    #[test]
+    fn ignores_the_callers_environment() {
+        let config = load(&[], &[], None).unwrap();
+        assert_eq!(config, Config { key_file: "node.key".into(), ..Config::default() });
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(Config::from_toml("ws_prot = 3001").is_err());
+        assert!(Config::from_toml("ws_port = \"high\"").is_err());
+        assert!(load(&[], &[], Some("ws_prot = 3001")).unwrap_err().contains("Invalid config file"));
+        assert!(load(&["--ws-prot", "3001"], &[], None).is_err());
+        assert!(load(&["--mdns", "maybe"], &[], None).is_err());
+        assert!(load(&[], &[("FOOTSTEPS_WS_PORT", "high")], None).is_err());
+        assert!(load(&["--config", "/nonexistent/footsteps.toml"], &[], None)
+            .unwrap_err()
+            .contains("Failed to read config file"));
+    }
+
+    #[test]
+    fn printed_config_loads_back() {
+        let config = load(&["--name", "bob", "--p2p-bind", "::", "--prover", "remote:prover.lan:7000"], &[], None).unwrap();
+        assert_eq!(Config::from_toml(&config.to_toml()).unwrap(), config);
+    }
+}
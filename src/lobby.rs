@@ -0,0 +1,185 @@
+// Named lobbies.
+//
+// Each lobby is its own gossipsub topic, so players only see proofs from the
+// lobby they're in. Members advertise their lobby on a shared discovery topic;
+// every node collects those adverts into a directory of open lobbies.
+use footsteps_core::GameParams;
+use libp2p::{gossipsub::IdentTopic, kad};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Topic every node subscribes to for lobby adverts
+pub const DISCOVERY_TOPIC: &str = "footsteps-lobbies";
+
+// Lobby a node joins at startup unless told otherwise
+pub const DEFAULT_LOBBY: &str = "default";
+
+const MAX_NAME_LEN: usize = 32;
+
+// Length of generated lobby codes
+const CODE_LEN: usize = 6;
+
+// Check a lobby name or code and bring it to the form its topic is derived from
+pub fn normalize_name(name: &str) -> Result<String, String> {
+    let name = name.trim().to_ascii_lowercase();
+    if name.is_empty() {
+        return Err("Lobby name must not be empty".to_string());
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!("Lobby name must be at most {} characters", MAX_NAME_LEN));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Lobby name may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(name)
+}
+
+// Short random code for a lobby created without a name
+pub fn generate_code() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..CODE_LEN].to_string()
+}
+
+// Gossipsub topic carrying a lobby's game traffic
+pub fn topic(name: &str) -> IdentTopic {
+    IdentTopic::new(format!("footsteps-lobby/{}", name))
+}
+
+// Match committed in every proof played in a lobby, or outside of any
+pub fn match_id(lobby: Option<&str>) -> [u8; 32] {
+    match lobby {
+        Some(name) => Sha256::digest(topic(name).hash().as_str()).into(),
+        None => [0; 32],
+    }
+}
+
+// DHT key every member of a lobby provides, so others can find them
+pub fn dht_key(name: &str) -> kad::RecordKey {
+    kad::RecordKey::new(&format!("footsteps/lobby/{}", name).into_bytes())
+}
+
+// An open lobby as shown to the frontend
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyListing {
+    pub name: String,
+    #[cfg_attr(test, ts(type = "number"))]
+    pub players: usize,
+    pub rules: LobbyRules,
+    pub map_hash: String, // Hex digest of the lobby's map
+}
+
+// A lobby's game rules as shown to the frontend
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "camelCase")]
+pub struct LobbyRules {
+    pub reveal_percent: u8,
+}
+
+impl From<GameParams> for LobbyRules {
+    fn from(params: GameParams) -> Self {
+        Self { reveal_percent: params.reveal_percent }
+    }
+}
+
+struct Advert {
+    rules: GameParams,
+    map_hash: [u8; 32],
+    seen: Instant,
+}
+
+// Lobbies advertised on the discovery topic, by lobby and then by advertising node
+#[derive(Default)]
+pub struct LobbyDirectory {
+    adverts: HashMap<String, HashMap<String, Advert>>,
+}
+
+impl LobbyDirectory {
+    // Record that `node_id` is in `lobby`, replacing any lobby it advertised before
+    pub fn record(&mut self, lobby: &str, node_id: &str, rules: GameParams, map_hash: [u8; 32]) {
+        self.remove_node(node_id);
+        self.adverts.entry(lobby.to_string()).or_default().insert(
+            node_id.to_string(),
+            Advert { rules, map_hash, seen: Instant::now() },
+        );
+    }
+
+    pub fn remove_node(&mut self, node_id: &str) {
+        for members in self.adverts.values_mut() {
+            members.remove(node_id);
+        }
+        self.adverts.retain(|_, members| !members.is_empty());
+    }
+
+    // Forget nodes that haven't advertised within `timeout`
+    pub fn expire(&mut self, timeout: Duration) {
+        for members in self.adverts.values_mut() {
+            members.retain(|_, advert| advert.seen.elapsed() < timeout);
+        }
+        self.adverts.retain(|_, members| !members.is_empty());
+    }
+
+    pub fn contains(&self, lobby: &str) -> bool {
+        self.adverts.contains_key(lobby)
+    }
+
+    // Open lobbies by name. A lobby's rules are those of its most recent advert.
+    pub fn listings(&self) -> Vec<LobbyListing> {
+        let mut listings: Vec<LobbyListing> = self
+            .adverts
+            .iter()
+            .filter_map(|(name, members)| {
+                let latest = members.values().max_by_key(|advert| advert.seen)?;
+                Some(LobbyListing {
+                    name: name.clone(),
+                    players: members.len(),
+                    rules: latest.rules.into(),
+                    map_hash: latest.map_hash.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                })
+            })
+            .collect();
+        listings.sort_by(|a, b| a.name.cmp(&b.name));
+        listings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_lobby_names() {
+        assert_eq!(normalize_name("  Friday-Night_1 ").unwrap(), "friday-night_1");
+        assert!(normalize_name("").is_err());
+        assert!(normalize_name("two words").is_err());
+        assert!(normalize_name("footsteps/other").is_err());
+        assert!(normalize_name(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+        assert_eq!(normalize_name(&generate_code()).unwrap().len(), CODE_LEN);
+    }
+
+    #[test]
+    fn counts_players_per_lobby() {
+        let mut directory = LobbyDirectory::default();
+        let rules = GameParams::default();
+        directory.record("a", "alice", rules, [0; 32]);
+        directory.record("a", "bob", rules, [0; 32]);
+        directory.record("b", "carol", rules, [0; 32]);
+
+        // Moving to another lobby takes the node out of its old one
+        directory.record("b", "bob", rules, [0; 32]);
+
+        let listings = directory.listings();
+        assert_eq!(listings.len(), 2);
+        assert_eq!((listings[0].name.as_str(), listings[0].players), ("a", 1));
+        assert_eq!((listings[1].name.as_str(), listings[1].players), ("b", 2));
+
+        directory.expire(Duration::ZERO);
+        assert!(directory.listings().is_empty());
+        assert!(!directory.contains("a"));
+    }
+}
@@ -12,26 +12,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod chain;
+mod chunks;
+mod codec;
+mod config;
+mod lobby;
 mod p2p;
-
-use footsteps_core::Outputs;
-use footsteps_methods::{FOOTSTEPS_GUEST_ELF, FOOTSTEPS_GUEST_ID};
-use risc0_zkvm::{default_prover, ExecutorEnv,  serde::to_vec};
+mod pipeline;
+mod protocol;
+mod prover;
+mod receipts;
+mod verifier;
+
+use clap::Parser;
+use footsteps_core::{
+    GameMap, GameParams, GridPos, Outputs, RevealBeacon, SALT_LEN,
+};
+use risc0_zkvm::{ProverOpts, ReceiptKind};
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::{Duration, Instant};
 // Add WebSocket imports
 use futures_util::{SinkExt, StreamExt};
-use serde_json::{json, Value};
+use protocol::{ClientMessage, ErrorCode, LocalState, Position, ServerMessage, StateSnapshot, PROTOCOL_VERSION};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, oneshot, broadcast};
+use tokio::sync::{mpsc, broadcast};
 use tokio_tungstenite::{accept_async, tungstenite::protocol::Message};
 
 // Define the same KeyInput enum as in the guest code
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-enum KeyInput {
+pub enum KeyInput {
     Up,
     Down,
     Left,
@@ -41,47 +52,361 @@ enum KeyInput {
     TestConstraint,
 }
 
-// Current position state shared between Bevy and proof generation thread
+impl KeyInput {
+    // Blocks moved by this key, matching the guest
+    fn offset(self) -> (i32, i32) {
+        match self {
+            KeyInput::Up => (0, 1),
+            KeyInput::Down => (0, -1),
+            KeyInput::Left => (-1, 0),
+            KeyInput::Right => (1, 0),
+            KeyInput::TestConstraint => (3, 3),
+            KeyInput::None => (0, 0),
+        }
+    }
+}
+
+// Latest verified state of a remote player, as seen by this node
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "camelCase")]
+pub struct RemotePlayer {
+    #[cfg_attr(test, ts(type = "Array<[number, number]>"))]
+    trail: Vec<GridPos>,      // Latest trail verified by ZK proof
+    #[cfg_attr(test, ts(type = "number"))]
+    last_proof_time: u64,     // Unix timestamp of the last verified proof
+    #[cfg_attr(test, ts(type = "number"))]
+    batch_count: usize,       // Number of batches verified for this player
+    status: String,
+    #[cfg_attr(test, ts(type = "number | null"))]
+    last_sequence: Option<u64>, // Sequence number of the last accepted batch
+    #[cfg_attr(test, ts(type = "number"))]
+    missed_batches: u64,      // Batches missing after the last accepted one
+    name: String,             // Name from the player's PlayerJoined or NodeInfo
+    custom_url: String,       // URL shared in the player's NodeInfo
+    #[cfg_attr(test, ts(type = "number"))]
+    last_seen: u64,           // Unix timestamp of the last message signed by this player
+    online: bool,             // Joined and heard from within the liveness timeout
+    #[serde(skip)]
+    epoch: Option<u64>,               // Epoch of the last accepted batch
+    #[serde(skip)]
+    end_commitment: Option<[u8; 32]>, // End commitment of the last accepted proof
+    #[serde(skip)]
+    journal_digest: Option<[u8; 32]>, // Journal digest of the last accepted proof, seeds the next reveal
+    #[serde(skip)]
+    beacon_epoch: Option<u64>,        // Epoch in which the player started seeding reveals from beacons
+    #[serde(skip)]
+    early_batches: BTreeMap<(u64, u64), (Outputs, [u8; 32])>, // Verified batches waiting on missing ones, by epoch and sequence
+    #[serde(skip)]
+    cuts: BTreeMap<(u64, u64), (Option<RevealBeacon>, bool)>, // Announced beacons of batches being proven, and whether they were fresh
+}
+
+// One player in the lobby roster sent to the frontend
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "camelCase")]
+pub struct RosterEntry {
+    peer_id: String,
+    name: String,
+    custom_url: String,
+    #[cfg_attr(test, ts(type = "number"))]
+    last_seen: u64,
+    status: String, // Verification status of the player's latest proof
+}
+
+// Current time as a Unix timestamp in seconds
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// Events buffered per WebSocket connection before it falls behind and resyncs
+const STATE_EVENT_BUFFER: usize = 256;
+
+// Verified or published batches remembered for checking reveal beacons
+const MAX_KNOWN_BATCHES: usize = 256;
+
+// What changed in the game state. Published on every mutation so WebSocket
+// connections can send the matching delta instead of polling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateEvent {
+    Local,          // Our position, proof status or batch progress
+    Player(String), // A remote player's verified state, status or presence
+    Lobbies,        // The open lobbies
+    Resync,         // Too much changed for a delta, e.g. after switching lobbies
+}
+
+// Current position state shared between the WebSocket clients and the proving pipeline
 pub struct GameState {
-    position_x: f32,
-    position_y: f32,
-    last_verified_x: f32, // Last position verified by ZK proof
-    last_verified_y: f32, // Last position verified by ZK proof
-    proof_start_x: f32,   // Starting position for the next proof
-    proof_start_y: f32,   // Starting position for the next proof
+    position: GridPos,
+    last_verified: GridPos, // End of our last published proof
+    proof_start: GridPos,   // Starting position for the next batch
     pending_keys: VecDeque<KeyInput>,
-    processing: bool,
-    next_process_time: Instant,
+    processing: bool,       // Whether any batch is being proven
+    last_input: Instant,    // When the last key was queued
     proof_status: String,
     last_batch_size: usize,
-    verified_trail: Vec<(f32, f32)>, // Trail verified by ZK proof (excluding final position)
+    position_salt: [u8; SALT_LEN], // Secret salt for this player's position commitments
+    map: GameMap,                  // Map enforced by the guest, mirrored for optimistic updates
+    params: GameParams,            // Game rules committed in every proof
+    last_journal_digest: [u8; 32], // Digest of our last published journal, seeds our next reveal
+    reveal_beacon: Option<RevealBeacon>, // Newest batch accepted from another player, also seeds it
+    known_batches: VecDeque<RevealBeacon>, // Batches verified or published lately, oldest first
+    epoch: u64,                    // Run of this node, committed in every proof
+    next_sequence: u64,            // Sequence number of our next batch
+    remote_players: HashMap<String, RemotePlayer>, // Verified state per remote PeerId
+    lobby: Option<String>,          // Lobby we're playing in, if any
+    lobbies: lobby::LobbyDirectory, // Open lobbies seen on the discovery topic
+    events: broadcast::Sender<StateEvent>, // Change events for WebSocket connections
 }
 
 impl GameState {
     pub fn new() -> Self {
         Self {
-            position_x: 0.0,
-            position_y: 0.0,
-            last_verified_x: 0.0,
-            last_verified_y: 0.0,
-            proof_start_x: 0.0,
-            proof_start_y: 0.0,
+            position: GridPos::default(),
+            last_verified: GridPos::default(),
+            proof_start: GridPos::default(),
             pending_keys: VecDeque::new(),
             processing: false,
-            next_process_time: Instant::now() + Duration::from_secs(5),
+            last_input: Instant::now(),
             proof_status: "Waiting for input".to_string(),
             last_batch_size: 0,
-            verified_trail: Vec::new(),
+            position_salt: *uuid::Uuid::new_v4().as_bytes(),
+            map: GameMap::default(),
+            params: GameParams::default(),
+            last_journal_digest: [0; 32],
+            reveal_beacon: None,
+            known_batches: VecDeque::new(),
+            epoch: 0,
+            next_sequence: 0,
+            remote_players: HashMap::new(),
+            lobby: None,
+            lobbies: lobby::LobbyDirectory::default(),
+            events: broadcast::channel(STATE_EVENT_BUFFER).0,
+        }
+    }
+
+    // Continue our position chain where a previous run left it
+    pub fn resume_chain(&mut self, chain: &chain::ChainState) {
+        self.position_salt = chain.salt;
+        self.epoch = chain.epoch;
+        self.position = chain.end;
+        self.last_verified = chain.end;
+        self.proof_start = chain.end;
+        self.last_journal_digest = chain.journal_digest;
+        self.next_sequence = 0;
+    }
+
+    // Remember a batch we verified or published, so beacons naming it can be checked
+    pub fn remember_batch(&mut self, batch: RevealBeacon) {
+        if self.knows_batch(&batch) {
+            return;
+        }
+        if self.known_batches.len() == MAX_KNOWN_BATCHES {
+            self.known_batches.pop_front();
+        }
+        self.known_batches.push_back(batch);
+    }
+
+    pub fn knows_batch(&self, batch: &RevealBeacon) -> bool {
+        self.known_batches.contains(batch)
+    }
+
+    // Whether we know any batch under this number; a player that forks its
+    // chain can have more than one
+    pub fn knows_batch_number(&self, player_id: &str, epoch: u64, sequence: u64) -> bool {
+        self.known_batches
+            .iter()
+            .any(|batch| batch.player_id == player_id && batch.epoch == epoch && batch.sequence == sequence)
+    }
+
+    // Epoch and sequence of the newest batch we know from a player
+    pub fn latest_batch(&self, player_id: &str) -> Option<(u64, u64)> {
+        self.known_batches
+            .iter()
+            .filter(|batch| batch.player_id == player_id)
+            .map(|batch| (batch.epoch, batch.sequence))
+            .max()
+    }
+
+    // Change events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<StateEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn notify(&self, event: StateEvent) {
+        // Failing only means no client is connected
+        let _ = self.events.send(event);
+    }
+
+    pub fn set_proof_status(&mut self, status: impl Into<String>) {
+        self.proof_status = status.into();
+        self.notify(StateEvent::Local);
+    }
+
+    // Switch lobbies. Players from the old lobby are forgotten.
+    pub fn set_lobby(&mut self, lobby: Option<String>) {
+        self.lobby = lobby;
+        self.remote_players.clear();
+        self.notify(StateEvent::Resync);
+    }
+
+    // Record a lobby advert from the discovery topic
+    pub fn record_lobby(&mut self, lobby: Option<&str>, node_id: &str, rules: GameParams, map_hash: [u8; 32]) {
+        match lobby {
+            Some(lobby) => self.lobbies.record(lobby, node_id, rules, map_hash),
+            None => self.lobbies.remove_node(node_id),
+        }
+        self.notify(StateEvent::Lobbies);
+    }
+
+    pub fn expire_lobbies(&mut self, timeout: Duration) {
+        self.lobbies.expire(timeout);
+        self.notify(StateEvent::Lobbies);
+    }
+
+    // Get or create the registry entry for a remote player, to change it
+    pub fn remote_player(&mut self, player_id: &str) -> &mut RemotePlayer {
+        self.notify(StateEvent::Player(player_id.to_string()));
+        self.remote_players
+            .entry(player_id.to_string())
+            .or_default()
+    }
+
+    // Record that a message signed by this player just arrived
+    pub fn mark_seen(&mut self, player_id: &str) {
+        let player = self.remote_player(player_id);
+        player.last_seen = unix_now();
+        player.online = true;
+    }
+
+    // Take players that haven't been heard from within `timeout` off the roster.
+    // Their proof chain is kept so they can pick up where they left off.
+    pub fn expire_players(&mut self, timeout: Duration) -> Vec<String> {
+        let cutoff = unix_now().saturating_sub(timeout.as_secs());
+        let mut expired = Vec::new();
+        for (player_id, player) in self.remote_players.iter_mut() {
+            if player.online && player.last_seen < cutoff {
+                player.online = false;
+                expired.push(player_id.clone());
+            }
+        }
+        for player_id in &expired {
+            self.notify(StateEvent::Player(player_id.clone()));
+        }
+        expired
+    }
+
+    // Players currently in the lobby, by name
+    pub fn roster(&self) -> Vec<RosterEntry> {
+        let mut roster: Vec<RosterEntry> = self
+            .remote_players
+            .iter()
+            .filter(|(_, player)| player.online)
+            .map(|(peer_id, player)| RosterEntry {
+                peer_id: peer_id.clone(),
+                name: player.name.clone(),
+                custom_url: player.custom_url.clone(),
+                last_seen: player.last_seen,
+                status: player.status.clone(),
+            })
+            .collect();
+        roster.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.peer_id.cmp(&b.peer_id)));
+        roster
+    }
+
+    // Our lobby and the open lobbies we know of, for the frontend
+    pub fn lobby_update(&self) -> ServerMessage {
+        ServerMessage::LobbyUpdate {
+            current: self.lobby.clone(),
+            lobbies: self.lobbies.listings(),
+        }
+    }
+
+    // Our own player, as sent in a local_update
+    pub fn local_state(&self) -> LocalState {
+        LocalState {
+            position: Position { x: self.position.x, y: self.position.y },
+            proof_status: self.proof_status.clone(),
+            processing: self.processing,
+            last_batch_size: self.last_batch_size,
+        }
+    }
+
+    // Everything the frontend shows about the game, as sent in a state_update
+    pub fn snapshot(&self, node_name: &str) -> StateSnapshot {
+        StateSnapshot {
+            position: Position { x: self.position.x, y: self.position.y },
+            proof_status: self.proof_status.clone(),
+            processing: self.processing,
+            last_batch_size: self.last_batch_size,
+            players: self.remote_players.clone(),
+            node_name: node_name.to_string(),
+        }
+    }
+}
+
+// Parse the receipt kind selected on the command line
+fn parse_receipt_kind(kind: &str) -> Result<ReceiptKind, String> {
+    match kind.to_lowercase().as_str() {
+        "composite" => Ok(ReceiptKind::Composite),
+        "succinct" => Ok(ReceiptKind::Succinct),
+        "groth16" => Ok(ReceiptKind::Groth16),
+        other => Err(format!("Unknown receipt kind '{}', expected composite, succinct or groth16", other)),
+    }
+}
+
+// Prover options for a receipt kind. Groth16 wrapping needs the x86 STARK-to-SNARK
+// prover, so other platforms fall back to succinct receipts.
+fn prover_opts(kind: ReceiptKind) -> ProverOpts {
+    match kind {
+        ReceiptKind::Composite => ProverOpts::composite(),
+        ReceiptKind::Succinct => ProverOpts::succinct(),
+        ReceiptKind::Groth16 if cfg!(target_arch = "x86_64") => ProverOpts::groth16(),
+        _ => {
+            println!("Groth16 receipts are not available on this platform, using succinct receipts");
+            ProverOpts::succinct()
         }
     }
 }
 
+// How long a new connection has to send its hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Read the next message from a client. Returns None once the connection closes.
+async fn next_client_message<S>(ws_receiver: &mut S) -> Option<Result<ClientMessage, String>>
+where
+    S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    while let Some(result) = ws_receiver.next().await {
+        match result {
+            Ok(Message::Text(text)) => {
+                println!("Received message: {}", text);
+                return Some(serde_json::from_str(&text).map_err(|e| format!("Invalid message: {}", e)));
+            }
+            Ok(Message::Binary(_)) => return Some(Err("Binary messages are not supported".to_string())),
+            Ok(Message::Close(_)) => return None,
+            Ok(_) => continue,
+            Err(e) => {
+                println!("Error receiving message: {:?}", e);
+                return None;
+            }
+        }
+    }
+    None
+}
+
 // Function to handle a WebSocket connection
 async fn handle_connection(
     ws_stream: TcpStream,
     game_state: Arc<Mutex<GameState>>,
     node_name: String,
-    connection_events: broadcast::Receiver<String>,
+    connection_events: broadcast::Receiver<ServerMessage>,
+    p2p: p2p::P2PHandle,
 ) {
     println!(
         "New WebSocket connection: {}",
@@ -98,187 +423,207 @@ async fn handle_connection(
 
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-    // Send initial game state
-    let initial_state = {
-        let state = game_state.lock().unwrap();
-        json!({
-            "type": "state_update",
-            "position": {
-                "x": state.position_x,
-                "y": state.position_y
-            },
-            "proofStatus": state.proof_status,
-            "processing": state.processing,
-            "lastBatchSize": state.last_batch_size,
-            "trail": state.verified_trail,
-            "nodeName": node_name,
-        })
+    // The client must open with a hello in our protocol version
+    let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, next_client_message(&mut ws_receiver)).await;
+    let refusal = match hello {
+        Ok(Some(Ok(ClientMessage::Hello { version }))) if version == PROTOCOL_VERSION => None,
+        Ok(Some(Ok(ClientMessage::Hello { version }))) => Some(ServerMessage::error(
+            ErrorCode::UnsupportedVersion,
+            format!("Node speaks protocol version {}, client sent {}", PROTOCOL_VERSION, version),
+        )),
+        Ok(Some(_)) => Some(ServerMessage::error(ErrorCode::HandshakeRequired, "Expected a hello message first")),
+        Ok(None) => return,
+        Err(_) => Some(ServerMessage::error(ErrorCode::HandshakeRequired, "No hello received in time")),
     };
-
-    if let Err(e) = ws_sender
-        .send(Message::Text(initial_state.to_string()))
-        .await
-    {
-        eprintln!("Error sending initial state: {:?}", e);
+    if let Some(refusal) = refusal {
+        println!("Refusing WebSocket client: {:?}", refusal);
+        let _ = ws_sender.send(Message::Text(refusal.to_json())).await;
+        let _ = ws_sender.close().await;
         return;
     }
 
+    // Subscribe before taking the snapshot, so no change slips in between
+    let mut state_events = game_state.lock().unwrap().subscribe();
+
+    // Greet the client and send the game as it stands: our state, the map
+    // for drawing walls and bounds, the lobby roster and the lobbies we know of
+    let (initial_state, initial_local, initial_roster, initial_lobbies, map) = {
+        let state = game_state.lock().unwrap();
+        (state.snapshot(&node_name), state.local_state(), state.roster(), state.lobby_update(), state.map.clone())
+    };
+    let greeting = [
+        ServerMessage::Welcome { version: PROTOCOL_VERSION, node_name: node_name.clone() },
+        ServerMessage::StateUpdate(initial_state),
+        ServerMessage::MapInfo { map },
+        ServerMessage::RosterUpdate { roster: initial_roster.clone() },
+        initial_lobbies.clone(),
+    ];
+    for message in &greeting {
+        if let Err(e) = ws_sender.send(Message::Text(message.to_json())).await {
+            eprintln!("Error sending initial state: {:?}", e);
+            return;
+        }
+    }
+
+    // Replies to this client's commands, sent by the update task
+    let (reply_tx, mut reply_rx) = mpsc::channel::<ServerMessage>(16);
+
     // Clone game state for the state update task
     let update_game_state = Arc::clone(&game_state);
     let update_node_name = node_name.clone();
     let mut connection_events_clone = connection_events.resubscribe();
 
-    // Spawn a task to periodically send state updates and connection events
+    // Spawn a task to push state changes, replies and connection events
     let update_task = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_millis(100));
-
-        // Keep track of the last sent state to detect changes
-        let mut last_sent_state = {
-            let state = update_game_state.lock().unwrap();
-            json!({
-                "position": {
-                    "x": state.position_x,
-                    "y": state.position_y
-                },
-                "proofStatus": state.proof_status.clone(),
-                "processing": state.processing,
-                "lastBatchSize": state.last_batch_size,
-                "trail": state.verified_trail.clone(),
-                "nodeName": update_node_name.clone(),
-            })
-        };
+        // Keep track of what was last sent, so events that change nothing visible aren't forwarded
+        let mut last_sent_local = initial_local;
+        let mut last_sent_roster = initial_roster;
+        let mut last_sent_lobbies = initial_lobbies;
 
         loop {
-            tokio::select! {
-                _ = interval.tick() => {
-                    // Get current state
-                    let current_state = {
-                        let state = update_game_state.lock().unwrap();
-                        json!({
-                            "position": {
-                                "x": state.position_x,
-                                "y": state.position_y
-                            },
-                            "proofStatus": state.proof_status.clone(),
-                            "processing": state.processing,
-                            "lastBatchSize": state.last_batch_size,
-                            "trail": state.verified_trail.clone(),
-                            "nodeName": update_node_name.clone(),
-                        })
-                    };
-
-                    // Check if state has changed
-                    if current_state != last_sent_state {
-                        // State has changed, send update
-                        let state_json = json!({
-                            "type": "state_update",
-                            "position": current_state["position"],
-                            "proofStatus": current_state["proofStatus"],
-                            "processing": current_state["processing"],
-                            "lastBatchSize": current_state["lastBatchSize"],
-                            "trail": current_state["trail"],
-                            "nodeName": current_state["nodeName"],
-                        });
-
-                        if let Err(e) = ws_sender.send(Message::Text(state_json.to_string())).await {
-                            eprintln!("Error sending state update: {:?}", e);
-                            break;
+            let messages = tokio::select! {
+                event = state_events.recv() => {
+                    let state = update_game_state.lock().unwrap();
+                    let mut messages = Vec::new();
+                    let resync = match event {
+                        Ok(StateEvent::Resync) => true,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            println!("WebSocket client missed {} state changes, resyncing", missed);
+                            true
                         }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                        Ok(_) => false,
+                    };
 
-                        // Update last sent state
-                        last_sent_state = current_state;
-                    }
-                }
-                Ok(event) = connection_events_clone.recv() => {
-                    // Parse the event message
-                    if let Ok(event_json) = serde_json::from_str::<serde_json::Value>(&event) {
-                        // Check if it's a node info event
-                        if event_json["type"] == "node_info" {
-                            // It's a node info event, forward it as is
-                            if let Err(e) = ws_sender.send(Message::Text(event)).await {
-                                eprintln!("Error sending node info event: {:?}", e);
-                                break;
+                    match event {
+                        Ok(StateEvent::Local) => {
+                            let local = state.local_state();
+                            if local != last_sent_local {
+                                messages.push(ServerMessage::LocalUpdate(local.clone()));
+                                last_sent_local = local;
                             }
-                        } else {
-                            // It's a regular connection event
-                            let event_json = json!({
-                                "type": "p2p_connection",
-                                "message": event
-                            });
-
-                            if let Err(e) = ws_sender.send(Message::Text(event_json.to_string())).await {
-                                eprintln!("Error sending connection event: {:?}", e);
-                                break;
+                        }
+                        Ok(StateEvent::Player(peer_id)) => {
+                            if let Some(player) = state.remote_players.get(&peer_id) {
+                                messages.push(ServerMessage::PlayerUpdate { peer_id, player: player.clone() });
                             }
                         }
-                    } else {
-                        // Couldn't parse as JSON, send as a regular connection event
-                        let event_json = json!({
-                            "type": "p2p_connection",
-                            "message": event
-                        });
-
-                        if let Err(e) = ws_sender.send(Message::Text(event_json.to_string())).await {
-                            eprintln!("Error sending connection event: {:?}", e);
-                            break;
+                        _ if resync => {
+                            messages.push(ServerMessage::StateUpdate(state.snapshot(&update_node_name)));
+                            last_sent_local = state.local_state();
                         }
+                        _ => {}
+                    }
+
+                    // A player's presence or status can change the roster
+                    let roster = state.roster();
+                    if resync || roster != last_sent_roster {
+                        messages.push(ServerMessage::RosterUpdate { roster: roster.clone() });
+                        last_sent_roster = roster;
+                    }
+
+                    let lobbies = state.lobby_update();
+                    if resync || lobbies != last_sent_lobbies {
+                        messages.push(lobbies.clone());
+                        last_sent_lobbies = lobbies;
                     }
+                    messages
+                }
+                Some(reply) = reply_rx.recv() => vec![reply],
+                // Peer connections and node info from the P2P network
+                Ok(event) = connection_events_clone.recv() => vec![event],
+            };
+
+            for message in messages {
+                if let Err(e) = ws_sender.send(Message::Text(message.to_json())).await {
+                    eprintln!("Error sending update: {:?}", e);
+                    return;
                 }
             }
         }
     });
 
     // Process incoming messages
-    while let Some(result) = ws_receiver.next().await {
-        match result {
-            Ok(msg) => {
-                if let Message::Text(text) = msg {
-                    println!("Received message: {}", text);
-
-                    // Parse the message as JSON
-                    if let Ok(json) = serde_json::from_str::<Value>(&text) {
-                        if let Some(msg_type) = json["type"].as_str() {
-                            match msg_type {
-                                "key_press" => {
-                                    if let Some(key_str) = json["key"].as_str() {
-                                        let key = match key_str {
-                                            "up" => KeyInput::Up,
-                                            "down" => KeyInput::Down,
-                                            "left" => KeyInput::Left,
-                                            "right" => KeyInput::Right,
-                                            "test" => KeyInput::TestConstraint,
-                                            _ => KeyInput::None,
-                                        };
-
-                                        // Add the key to the pending keys queue
-                                        {
-                                            let mut state = game_state.lock().unwrap();
-                                            state.pending_keys.push_back(key);
-
-                                            // Update player position immediately for responsive UI
-                                            let (dx, dy) = match key {
-                                                KeyInput::Up => (0.0, 1.0),
-                                                KeyInput::Down => (0.0, -1.0),
-                                                KeyInput::Left => (-1.0, 0.0),
-                                                KeyInput::Right => (1.0, 0.0),
-                                                KeyInput::TestConstraint => (3.0, 3.0),
-                                                KeyInput::None => (0.0, 0.0),
-                                            };
-
-                                            state.position_x += dx;
-                                            state.position_y += dy;
-                                        }
-                                    }
-                                }
-                                _ => println!("Unknown message type: {}", msg_type),
-                            }
-                        }
+    while let Some(message) = next_client_message(&mut ws_receiver).await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Bad message from WebSocket client: {}", e);
+                let _ = reply_tx.send(ServerMessage::error(ErrorCode::BadRequest, e)).await;
+                continue;
+            }
+        };
+
+        match message {
+            ClientMessage::Hello { .. } => {
+                let error = ServerMessage::error(ErrorCode::BadRequest, "Handshake already done");
+                let _ = reply_tx.send(error).await;
+            }
+            ClientMessage::KeyPress { key } => {
+                let key = KeyInput::from(key);
+
+                // Add the key to the pending keys queue
+                let mut state = game_state.lock().unwrap();
+
+                // Update player position immediately for responsive UI.
+                // Apply the same map rules as the guest, so a blocked move
+                // is dropped here instead of failing the whole batch.
+                // The test key is still queued to exercise the constraint check.
+                let (dx, dy) = key.offset();
+                let new_position = state.position.offset(dx, dy);
+                if key != KeyInput::TestConstraint && !state.map.is_walkable(new_position) {
+                    println!("Ignoring {:?}: {:?} is a wall or off the map", key, new_position);
+                    continue;
+                }
+
+                state.pending_keys.push_back(key);
+                state.position = new_position;
+                state.last_input = Instant::now();
+                state.notify(StateEvent::Local);
+            }
+            ClientMessage::ListLobbies => {
+                let lobbies = game_state.lock().unwrap().lobby_update();
+                let _ = reply_tx.send(lobbies).await;
+            }
+            ClientMessage::CreateLobby { name } => {
+                // Creating without a name makes up a code others can join with
+                let name = match name {
+                    Some(name) => lobby::normalize_name(&name),
+                    None => Ok(lobby::generate_code()),
+                };
+                let name = name.and_then(|name| {
+                    if game_state.lock().unwrap().lobbies.contains(&name) {
+                        Err(format!("Lobby {} already exists", name))
+                    } else {
+                        Ok(name)
+                    }
+                });
+                match name {
+                    Ok(name) => p2p.join_lobby(name).await,
+                    Err(message) => {
+                        let _ = reply_tx.send(ServerMessage::LobbyError { message }).await;
                     }
                 }
             }
-            Err(e) => {
-                println!("Error receiving message: {:?}", e);
-                break;
+            ClientMessage::JoinLobby { name } => match lobby::normalize_name(&name) {
+                Ok(name) => p2p.join_lobby(name).await,
+                Err(message) => {
+                    let _ = reply_tx.send(ServerMessage::LobbyError { message }).await;
+                }
+            },
+            ClientMessage::LeaveLobby => p2p.leave_lobby().await,
+            ClientMessage::Resync => {
+                let snapshot = {
+                    let state = game_state.lock().unwrap();
+                    [
+                        ServerMessage::StateUpdate(state.snapshot(&node_name)),
+                        ServerMessage::RosterUpdate { roster: state.roster() },
+                        state.lobby_update(),
+                    ]
+                };
+                for message in snapshot {
+                    let _ = reply_tx.send(message).await;
+                }
             }
         }
     }
@@ -290,75 +635,114 @@ async fn handle_connection(
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Flags, environment variables and the config file, see config.rs
+    let cli = config::Cli::parse();
+    let print_config = cli.print_config;
+    let config = config::Config::load(cli)?;
+    if print_config {
+        print!("{}", config.to_toml());
+        return Ok(());
+    }
 
     println!("Welcome to Footsteps!");
 
-    // Get node name from command line
-    let node_name = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "node".to_string());
-    let ws_port = std::env::args()
-        .nth(2)
-        .unwrap_or_else(|| "3001".to_string())
-        .parse::<u16>()?;
-    let p2p_port = std::env::args()
-        .nth(3)
-        .unwrap_or_else(|| "9000".to_string())
-        .parse::<u16>()?;
-    
-    // Parse known peers from command line (format: host1:port1,host2:port2,...)
-    let peers_arg = std::env::args().nth(4).unwrap_or_else(|| String::new());
-    
-    // Get custom URL from command line (for sharing with other nodes)
-    let custom_url = std::env::args().nth(5).unwrap_or_else(|| String::new());
-    
-    let known_peers: Vec<(String, u16)> = if !peers_arg.is_empty() {
-        peers_arg
-            .split(',')
-            .filter_map(|peer_str| {
-                let parts: Vec<&str> = peer_str.split(':').collect();
-                if parts.len() == 2 {
-                    if let Ok(port) = parts[1].parse::<u16>() {
-                        Some((parts[0].to_string(), port))
-                    } else {
-                        eprintln!("Invalid port in peer specification: {}", peer_str);
-                        None
-                    }
-                } else {
-                    eprintln!("Invalid peer specification: {}", peer_str);
-                    None
-                }
-            })
-            .collect()
-    } else {
-        Vec::new()
+    let node_name = config.name.clone();
+    let custom_url = config.custom_url.clone();
+
+    // Percentage of each batch revealed to other players; every peer must use the same value
+    let reveal_percent = config.reveal_percent;
+    if reveal_percent > 100 {
+        return Err(format!("Reveal percentage must be between 0 and 100, got {}", reveal_percent).into());
+    }
+
+    // Proof verification workers and how many received proofs may wait for one
+    let verifier_config = verifier::VerifierConfig {
+        workers: config.verifier_workers.max(1),
+        max_queued: config.max_queued_proofs.max(1),
+        // Whether to accept other players' fake receipts
+        accept_dev_receipts: config.accept_dev_receipts,
+    };
+
+    // Kind of receipt to publish
+    let prover_opts = prover_opts(parse_receipt_kind(&config.receipt_kind)?);
+
+    // Other guest image IDs whose proofs are accepted alongside our own
+    let extra_image_ids = config
+        .extra_image_ids
+        .iter()
+        .filter(|id| !id.trim().is_empty())
+        .map(|id| verifier::parse_image_id(id))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Whether proofs are gossiped whole or announced and fetched on demand
+    let proof_distribution = config.proof_distribution.parse::<p2p::ProofDistribution>()?;
+
+    // DHT bootstrap peers, each ending in /p2p/<peer id>
+    let bootstrap = p2p::parse_bootstrap(&config.bootstrap.join(","))?;
+
+    // Proof pipeline: a zero idle flush turns it off
+    let pipeline_config = pipeline::PipelineConfig {
+        batch_interval: Duration::from_millis(config.batch_interval_ms),
+        max_batch_size: config.max_batch_size.max(1),
+        idle_flush: (config.idle_flush_ms > 0).then(|| Duration::from_millis(config.idle_flush_ms)),
+        max_in_flight: config.max_in_flight.max(1),
     };
 
+    let prover_backend = config.prover.parse::<prover::ProverBackend>()?;
+    if prover_backend == prover::ProverBackend::Dev {
+        println!("WARNING: dev-mode prover publishes fake receipts, which strict peers reject");
+    }
+
+    // Lobby to join at startup, if any
+    let startup_lobby = match config.lobby.trim() {
+        "" => None,
+        name => Some(lobby::normalize_name(name)?),
+    };
+
+    // Known peers are multiaddrs, e.g. /dns4/example.com/udp/9000/quic-v1, or host:port for TCP
+    let known_peers = p2p::parse_known_peers(&config.peers.join(","))
+        .map_err(|errors| format!("Invalid known peers:\n{}", errors))?;
+
     println!(
         "Starting {} node with WebSocket port {}, P2P port {}, and custom URL: {}",
-        node_name, ws_port, p2p_port, if custom_url.is_empty() { "none" } else { &custom_url }
+        node_name, config.ws_port, config.p2p_port, if custom_url.is_empty() { "none" } else { &custom_url }
     );
     if !known_peers.is_empty() {
         println!("Known peers:");
-        for (host, port) in &known_peers {
-            println!("  {}:{}", host, port);
+        for addr in &known_peers {
+            println!("  {}", addr);
         }
     } else {
         println!("No known peers specified. Only local discovery will be used.");
     }
 
-    // Initialize game state
-    let game_state = Arc::new(Mutex::new(GameState::new()));
+    // Initialize game state, continuing our position chain from the last run
+    let chain_file = chain::ChainState::path(&config.key_file);
+    let chain = chain::ChainState::resume(&chain_file)?;
+    let mut initial_state = GameState::new();
+    initial_state.params = GameParams { reveal_percent };
+    initial_state.resume_chain(&chain);
+    let game_state = Arc::new(Mutex::new(initial_state));
 
     // Start the P2P node
-    let (p2p_sender, p2p_connection_rx) = p2p::start_p2p_node(
-        node_name.clone(),
-        Arc::clone(&game_state),
-        p2p_port,
+    let p2p_config = p2p::P2PConfig {
+        node_name: node_name.clone(),
+        listen_ip: config.p2p_bind,
+        listen_port: config.p2p_port,
         known_peers,
         custom_url,
-    )
-    .await?;
+        key_file: config.key_file.clone(),
+        verifier: verifier_config,
+        extra_image_ids,
+        proof_distribution,
+        lobby: startup_lobby,
+        bootstrap,
+        mdns: config.mdns,
+        lobby_lookup_interval: p2p::LOBBY_LOOKUP_INTERVAL,
+    };
+    let (p2p_node, p2p_connection_rx) = p2p::start_p2p_node(p2p_config, Arc::clone(&game_state)).await?;
+    let p2p_sender = p2p_node.sender.clone();
+    let local_peer_id = p2p_node.peer_id;
 
     // Create a channel for sending proofs from the thread to the main async context
     let (proof_tx, mut proof_rx) = mpsc::channel::<p2p::P2PMessage>(100);
@@ -366,151 +750,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Clone p2p_sender for the async task
     let p2p_sender_clone = p2p_sender.clone();
 
-    // Clone game state for the proof generation thread
-    let proof_game_state = Arc::clone(&game_state);
-    let proof_node_name = node_name.clone();
-
-    // Spawn a thread to handle periodic proof generation
-    thread::spawn(move || {
-        loop {
-            // Sleep for a short time to prevent CPU hogging
-            thread::sleep(Duration::from_millis(100));
-
-            // Check if it's time to process
-            let now = Instant::now();
-            let should_update_timer = {
-                let state = proof_game_state.lock().unwrap();
-                now >= state.next_process_time
-            };
-
-            if should_update_timer {
-                // Update next process time
-                {
-                    let mut state = proof_game_state.lock().unwrap();
-                    state.next_process_time = Instant::now() + Duration::from_secs(5);
-                }
-
-                // Check if there are pending key presses to process
-                let (should_process, key_inputs, current_position) = {
-                    let mut state = proof_game_state.lock().unwrap();
-
-                    // Only process if there are pending keys and we're not already processing
-                    let should_process = !state.pending_keys.is_empty() && !state.processing;
-
-                    if should_process {
-                        // Mark as processing to prevent concurrent processing
-                        state.processing = true;
-                        state.proof_status = "Generating proof...".to_string();
-
-                        // Get all pending key presses
-                        let keys: Vec<KeyInput> = state.pending_keys.drain(..).collect();
-                        state.last_batch_size = keys.len();
-
-                        // Use the proof_start position as the starting point for verification
-                        // This is the position before any pending keys were applied
-                        println!(
-                            "Proof starting position: ({}, {})",
-                            state.proof_start_x, state.proof_start_y
-                        );
-                        let position = (state.proof_start_x, state.proof_start_y);
-
-                        // After processing this batch, the next proof should start from the current position
-                        state.proof_start_x = state.position_x;
-                        state.proof_start_y = state.position_y;
-
-                        (true, keys, position)
-                    } else {
-                        (false, Vec::new(), (0.0, 0.0))
-                    }
-                };
-
-                // Process the batch of key presses if needed
-                if should_process {
-                    println!(
-                        "Processing batch of {} key presses: {:?}",
-                        key_inputs.len(),
-                        key_inputs
-                    );
-
-                    // Create the execution environment with the key inputs, current position, and game map
-                    let env = ExecutorEnv::builder()
-                        .write(&key_inputs)
-                        .unwrap()
-                        .write(&current_position)
-                        .unwrap()
-                        .build()
-                        .unwrap();
-
-                    // Get the prover inside the thread
-                    let prover = default_prover();
-
-                    println!("Generating proof for batch (this may take a while)...");
-                    let start_time = Instant::now();
-
-                    // Generate the proof
-                    let receipt = match prover.prove(env, FOOTSTEPS_GUEST_ELF) {
-                        Ok(receipt_result) => {
-                            let elapsed = start_time.elapsed();
-                            println!("Proof generated in {:.2} seconds", elapsed.as_secs_f32());
-
-                            {
-                                let mut state = proof_game_state.lock().unwrap();
-                                state.processing = false;
-                                state.proof_status =
-                                    format!("Proof generated in {:.2}s", elapsed.as_secs_f32());
-                            }
-
-                            receipt_result.receipt
-                        }
-                        Err(e) => {
-                            println!("Error generating proof: {:?}", e);
-                            println!("This may be due to a constraint violation in one of the key presses.");
-
-                            // Mark as no longer processing
-                            let mut state = proof_game_state.lock().unwrap();
-                            state.processing = false;
-                            state.proof_status = "Proof failed: Constraint violation".to_string();
-
-                            // Revert position to the last valid state (the last verified position)
-                            state.position_x = state.last_verified_x;
-                            state.position_y = state.last_verified_y;
-
-                            // Don't update position for failed proofs
-                            println!(
-                                "Position reverted to last valid state: ({}, {})",
-                                state.last_verified_x, state.last_verified_y
-                            );
-                            continue;
-                        }
-                    };
-
-                    // send the proof to the p2p network
-                    // for other players to verify
-                    let p2p_msg = p2p::P2PMessage::Proof {
-                        player_id: format!("{}-player", proof_node_name),
-                        receipt: receipt,
-                        ImageID: FOOTSTEPS_GUEST_ID,
-                    };
-
-
-                    // let serialized = to_vec(&receipt).unwrap();
-                    // println!("Receipt size: {} bytes", serialized.len());
-                    // println!(
-                    //     "Receipt size: {:.2} MB",
-                    //     serialized.len() as f64 / 1_048_576.0
-                    // );
+    // Prove batches for other nodes that offload proving to us
+    if !config.prover_listen.is_empty() {
+        let addr = &config.prover_listen;
+        let listener = std::net::TcpListener::bind(addr)?;
+        println!("Serving proofs for remote nodes on {}", addr);
+        let local_prover = prover::ProverBackend::Local.build(prover_opts.clone());
+        std::thread::spawn(move || prover::serve(listener, local_prover));
+    }
 
-                    // Send the proof to the main async context
-                    if let Err(e) = proof_tx.blocking_send(p2p_msg) {
-                        eprintln!("Error sending proof to main context: {:?}", e);
-                    }
-                }
-            }
-        }
-    });
+    // Cut key presses into batches and prove them, publishing proofs in order
+    let movement_prover = prover_backend.build(prover_opts);
+    println!("Using the {} prover", movement_prover.name());
+    pipeline::Pipeline::new(
+        pipeline_config,
+        Arc::clone(&game_state),
+        local_peer_id.to_string(),
+        movement_prover,
+        proof_tx,
+        chain_file,
+    )
+    .spawn();
 
     // Create a broadcast channel for connection events
-    let (connection_tx, _) = broadcast::channel::<String>(100);
+    let (connection_tx, _) = broadcast::channel::<ServerMessage>(100);
 
     tokio::spawn(async move {
         while let Some(proof_msg) = proof_rx.recv().await {
@@ -526,7 +789,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tokio::spawn(async move {
         let mut p2p_connection_rx = p2p_connection_rx;
         while let Some(event) = p2p_connection_rx.recv().await {
-            println!("P2P connection event: {}", event);
+            println!("P2P connection event: {:?}", event);
             if let Err(e) = connection_tx_clone.send(event) {
                 eprintln!("Error broadcasting connection event: {:?}", e);
             }
@@ -534,21 +797,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     });
 
     // Set up the WebSocket server
-    let addr = format!("0.0.0.0:{}", ws_port);
-    let listener = TcpListener::bind(&addr).await?;
+    let addr = std::net::SocketAddr::new(config.ws_bind, config.ws_port);
+    let listener = TcpListener::bind(addr).await?;
     println!("WebSocket server listening on: {}", addr);
-    println!("Connect your Next.js app to ws://<ip>:{}", ws_port);
-
-    // Accept and handle WebSocket connections
-    while let Ok((stream, _)) = listener.accept().await {
-        let game_state_clone = Arc::clone(&game_state);
-        let node_name_clone = node_name.clone();
-        let connection_events = connection_tx.subscribe();
-
-        tokio::spawn(async move {
-            handle_connection(stream, game_state_clone, node_name_clone, connection_events).await;
-        });
+    println!("Connect your Next.js app to ws://<ip>:{}", config.ws_port);
+
+    // Accept and handle WebSocket connections until Ctrl-C
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let Ok((stream, _)) = accepted else {
+                    break;
+                };
+                let game_state_clone = Arc::clone(&game_state);
+                let node_name_clone = node_name.clone();
+                let connection_events = connection_tx.subscribe();
+                let p2p_handle = p2p_node.clone();
+
+                tokio::spawn(async move {
+                    handle_connection(stream, game_state_clone, node_name_clone, connection_events, p2p_handle).await;
+                });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                println!("Shutting down...");
+                break;
+            }
+        }
     }
 
+    // Let the other players know we're leaving
+    p2p_node.shutdown().await;
+
     Ok(())
 }
@@ -1,36 +1,81 @@
-use crate::GameState;
-use footsteps_core::Outputs;
-use footsteps_methods::{FOOTSTEPS_GUEST_ELF, FOOTSTEPS_GUEST_ID};
+use crate::chunks::{self, ChunkEvent, ChunkFailure, Reassembler};
+use crate::codec;
+use crate::lobby;
+use crate::protocol::ServerMessage;
+use crate::receipts::{self, BatchId, ReceiptCache, ReceiptRequest, ReceiptResponse};
+use crate::verifier::{image_id_hex, VerifierConfig, VerifierPool, VerifyError, VerifyJob};
+use crate::{unix_now, GameState, RemotePlayer};
+use footsteps_core::{journal_digest, reveal_seed, GameParams, Outputs, RevealBeacon};
+use footsteps_methods::FOOTSTEPS_GUEST_ID;
 use futures::StreamExt;
+use futures::future::Either;
 use libp2p::{
-    core::upgrade,
-    gossipsub::{self, IdentTopic, MessageAuthenticity},
+    core::{muxing::StreamMuxerBox, transport::OrTransport, upgrade},
+    dns,
+    gossipsub::{
+        self, IdentTopic, MessageAcceptance, MessageAuthenticity, MessageId, PeerScoreParams,
+        PeerScoreThresholds, TopicScoreParams,
+    },
+    identify,
     identity::Keypair,
+    kad::{self, store::MemoryStore},
     mdns::{self, tokio::Behaviour as MdnsBehaviour},
-    noise,
-    swarm::{NetworkBehaviour, SwarmBuilder, SwarmEvent},
-    tcp, yamux, Multiaddr, PeerId, Transport,
+    multiaddr::Protocol,
+    noise, quic,
+    request_response::{self, ProtocolSupport},
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmBuilder, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Transport,
 };
-use risc0_zkvm::Receipt;
+use risc0_zkvm::{InnerReceipt, Receipt};
 use serde::{Deserialize, Serialize};
-use std::thread;
 use std::{
+    cmp::Ordering,
+    collections::HashMap,
     error::Error,
+    fs,
+    net::IpAddr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     time::Duration,
 };
-use tokio::sync::mpsc;
-use serde_json;
+use tokio::sync::{mpsc, oneshot};
 
 // Message types for our P2P network
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum P2PMessage {
-    // Player movement with proof
+    // Player movement with proof. player_id is the prover's PeerId
     Proof { player_id: String, receipt: Receipt, ImageID: [u32; 8] },
+    // Stand-in for a Proof; the receipt itself is fetched over the receipt protocol
+    ProofAnnouncement {
+        player_id: String,
+        epoch: u64,
+        sequence: u64,
+        journal_digest: [u8; 32],
+        receipt_hash: [u8; 32],
+        ImageID: [u32; 8],
+    },
+    // Part of a Proof too large for one gossip message; see chunks.rs
+    ProofChunk {
+        player_id: String,
+        content_hash: [u8; 32],
+        index: u32,
+        total: u32,
+        data: Vec<u8>,
+    },
     // Player joined
     PlayerJoined { player_id: String, name: String },
     // Player left
     PlayerLeft { player_id: String },
+    // Liveness ping, sent every HEARTBEAT_INTERVAL
+    Heartbeat { player_id: String },
+    // Lobby a node is in, sent on the discovery topic. None when it leaves.
+    LobbyAdvert {
+        node_id: String,
+        lobby: Option<String>,
+        rules: GameParams,
+        map_hash: [u8; 32],
+    },
     // Node identification with custom data
     NodeInfo { 
         node_id: String, 
@@ -38,56 +83,398 @@ pub enum P2PMessage {
         custom_url: String,
         // Add any other custom fields you want to exchange
     },
+    // Batch the player started proving, and the beacon its reveal was seeded
+    // from, sent before the proof so the beacon is judged while it's recent
+    BatchCut {
+        player_id: String,
+        epoch: u64,
+        sequence: u64,
+        beacon: Option<RevealBeacon>,
+    },
+}
+
+impl P2PMessage {
+    // Identity the message claims to come from. Must be the PeerId that signed it.
+    fn claimed_id(&self) -> &str {
+        match self {
+            P2PMessage::Proof { player_id, .. }
+            | P2PMessage::ProofAnnouncement { player_id, .. }
+            | P2PMessage::BatchCut { player_id, .. }
+            | P2PMessage::ProofChunk { player_id, .. }
+            | P2PMessage::PlayerJoined { player_id, .. }
+            | P2PMessage::PlayerLeft { player_id }
+            | P2PMessage::Heartbeat { player_id } => player_id,
+            P2PMessage::NodeInfo { node_id, .. } | P2PMessage::LobbyAdvert { node_id, .. } => node_id,
+        }
+    }
+}
+
+// Largest gossipsub message; bigger proofs are sent in chunks
+const MAX_TRANSMIT_SIZE: usize = 1024 * 1024;
+
+// Room left for gossipsub's framing and signature around our payload
+const GOSSIP_OVERHEAD: usize = 16 * 1024;
+
+// How long a chunked proof may take to arrive in full
+const CHUNK_TRANSFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How often we tell peers we're still here
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Players not heard from for this long are taken off the roster
+const PLAYER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Our own DHT, separate from the public IPFS one
+const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/footsteps/kad/1.0.0");
+
+// Protocol version exchanged by identify
+const IDENTIFY_PROTOCOL: &str = "/footsteps/id/1.0.0";
+
+// How often we look up the other members of our lobby in the DHT, by default
+pub const LOBBY_LOOKUP_INTERVAL: Duration = Duration::from_secs(30);
+
+// Parse a known peer given either as a multiaddr, e.g. /dns4/example.com/udp/9000/quic-v1
+// or /ip6/::1/tcp/9000/p2p/<peer id>, or in the older host:port form, which means TCP.
+// Hostnames are resolved when dialing.
+pub fn parse_known_peer(spec: &str) -> Result<Multiaddr, String> {
+    let spec = spec.trim();
+    let addr = if spec.starts_with('/') {
+        spec.parse::<Multiaddr>()
+            .map_err(|e| format!("Invalid peer address '{}': {}", spec, e))?
+    } else {
+        let (host, port) = spec
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Invalid peer '{}': expected host:port or a multiaddr", spec))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid peer '{}': '{}' is not a port number", spec, port))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let host = match host.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V4(ip)) => Protocol::Ip4(ip),
+            Ok(std::net::IpAddr::V6(ip)) => Protocol::Ip6(ip),
+            Err(_) if !host.is_empty() => Protocol::Dns(host.into()),
+            Err(_) => return Err(format!("Invalid peer '{}': missing host", spec)),
+        };
+        Multiaddr::empty().with(host).with(Protocol::Tcp(port))
+    };
+
+    check_dialable(&addr).map_err(|e| format!("Invalid peer '{}': {}", spec, e))?;
+    Ok(addr)
+}
+
+// Parse a comma-separated list of known peers, reporting every invalid entry
+pub fn parse_known_peers(list: &str) -> Result<Vec<Multiaddr>, String> {
+    let mut peers = Vec::new();
+    let mut errors = Vec::new();
+    for spec in list.split(',').filter(|spec| !spec.trim().is_empty()) {
+        match parse_known_peer(spec) {
+            Ok(addr) => peers.push(addr),
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(peers)
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+// Check that a multiaddr is something our transports can dial:
+// an IP or DNS name, then TCP or UDP/QUIC, optionally ending in /p2p/<peer id>
+fn check_dialable(addr: &Multiaddr) -> Result<(), String> {
+    let mut protocols = addr.iter();
+    match protocols.next() {
+        Some(Protocol::Ip4(_) | Protocol::Ip6(_) | Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_)) => {}
+        _ => return Err("must start with /ip4, /ip6, /dns, /dns4 or /dns6".to_string()),
+    }
+    match (protocols.next(), protocols.next()) {
+        (Some(Protocol::Tcp(_)), None) | (Some(Protocol::Tcp(_)), Some(Protocol::P2p(_))) => {}
+        (Some(Protocol::Udp(_)), Some(Protocol::QuicV1)) => {
+            if !matches!(protocols.next(), None | Some(Protocol::P2p(_))) {
+                return Err("unexpected protocols after /quic-v1".to_string());
+            }
+        }
+        _ => return Err("expected /tcp/<port> or /udp/<port>/quic-v1 after the host".to_string()),
+    }
+    if protocols.next().is_some() {
+        return Err("unexpected protocols after /p2p/<peer id>".to_string());
+    }
+    Ok(())
+}
+
+// Parse comma-separated bootstrap multiaddrs. Each must end in /p2p/<peer id>
+// so the peer can be added to the DHT routing table.
+pub fn parse_bootstrap(list: &str) -> Result<Vec<Multiaddr>, String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(|addr| {
+            let multiaddr = addr
+                .parse::<Multiaddr>()
+                .map_err(|e| format!("Invalid bootstrap address '{}': {}", addr, e))?;
+            match multiaddr.iter().last() {
+                Some(Protocol::P2p(_)) => Ok(multiaddr),
+                _ => Err(format!("Bootstrap address '{}' must end in /p2p/<peer id>", addr)),
+            }
+        })
+        .collect()
+}
+
+// How our proofs reach other players
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProofDistribution {
+    // Gossip a small announcement; interested peers fetch the receipt on demand
+    #[default]
+    Announce,
+    // Gossip the full receipt to every peer
+    Broadcast,
+}
+
+impl std::str::FromStr for ProofDistribution {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.to_ascii_lowercase().as_str() {
+            "announce" => Ok(ProofDistribution::Announce),
+            "broadcast" => Ok(ProofDistribution::Broadcast),
+            _ => Err(format!("Unknown proof distribution '{}', expected announce or broadcast", mode)),
+        }
+    }
+}
+
+// Reasons a gossipsub message is dropped before it reaches the game state
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageRejection {
+    // Message carries no signed source
+    Unsigned,
+    // Message was published by this node
+    OwnMessage,
+    // Payload is not a valid P2PMessage
+    Undecodable(String),
+    // Claimed player_id/node_id doesn't match the signing PeerId
+    IdentityMismatch { claimed: String, source: PeerId },
+}
+
+impl std::fmt::Display for MessageRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MessageRejection::Unsigned => write!(f, "message has no signed source"),
+            MessageRejection::OwnMessage => write!(f, "message was published by this node"),
+            MessageRejection::Undecodable(e) => write!(f, "could not decode message: {}", e),
+            MessageRejection::IdentityMismatch { claimed, source } => {
+                write!(f, "message claims to be from {} but was signed by {}", claimed, source)
+            }
+        }
+    }
+}
+
+// Decode a gossipsub message and check that the identity it claims is the
+// PeerId that signed it. Gossipsub's strict validation has already checked
+// the signature against `message.source`.
+pub fn authenticate_message(
+    local_peer_id: &PeerId,
+    message: &gossipsub::Message,
+) -> Result<P2PMessage, MessageRejection> {
+    let source = message.source.ok_or(MessageRejection::Unsigned)?;
+    if source == *local_peer_id {
+        return Err(MessageRejection::OwnMessage);
+    }
+
+    let p2p_msg = codec::decode(&message.data)
+        .map_err(|e| MessageRejection::Undecodable(e.to_string()))?;
+
+    if p2p_msg.claimed_id() != source.to_string() {
+        return Err(MessageRejection::IdentityMismatch {
+            claimed: p2p_msg.claimed_id().to_string(),
+            source,
+        });
+    }
+
+    Ok(p2p_msg)
 }
 
 // Define the network behavior
 #[derive(NetworkBehaviour)]
 struct GameBehaviour {
     gossipsub: gossipsub::Behaviour,
-    mdns: MdnsBehaviour,
+    mdns: Toggle<MdnsBehaviour>,
+    kad: kad::Behaviour<MemoryStore>,
+    identify: identify::Behaviour,
+    receipts: request_response::cbor::Behaviour<ReceiptRequest, ReceiptResponse>,
+}
+
+// A receipt we're fetching: an announced one, or a batch missing from a player's chain
+struct PendingFetch {
+    player_id: String,
+    image_id: [u32; 8],
+    request: ReceiptRequest,
+    journal_digest: Option<[u8; 32]>,   // Digest the announcement promised
+    messages: Vec<(MessageId, PeerId)>, // Announcement held back until the receipt is verified
+    fallback: Option<PeerId>,           // The prover itself, asked if the first peer can't serve it
+}
+
+// Most batches requested at once to fill a gap in a player's chain
+const MAX_GAP_FETCH: u64 = 8;
+
+// Verified batches kept per player while the ones before them are fetched
+const MAX_EARLY_BATCHES: usize = 8;
+
+// How many batches a reveal beacon may trail the newest one we know from its
+// player when its batch was cut: the newest, or the one before it
+const BEACON_LAG: u64 = 1;
+
+// Batch cuts kept per player until their proofs arrive
+const MAX_CUTS: usize = 16;
+
+// Load the node's identity from `path`, or generate one and save it there.
+// The same keypair is used for the PeerId, the noise handshake and gossipsub signing.
+pub fn load_or_create_keypair(path: &Path) -> Result<Keypair, Box<dyn Error>> {
+    if path.exists() {
+        let bytes = fs::read(path)?;
+        let keypair = Keypair::from_protobuf_encoding(&bytes)
+            .map_err(|e| format!("Invalid key file {}: {}", path.display(), e))?;
+        println!("Loaded identity from {}", path.display());
+        return Ok(keypair);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    fs::write(path, keypair.to_protobuf_encoding()?)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    println!("Generated new identity and saved it to {}", path.display());
+    Ok(keypair)
+}
+
+// Settings for starting a P2P node
+pub struct P2PConfig {
+    pub node_name: String,
+    pub listen_ip: IpAddr, // Interface to listen on; unspecified means all of them
+    pub listen_port: u16,
+    pub known_peers: Vec<Multiaddr>,     // Peers to dial at startup
+    pub custom_url: String,              // Custom URL to share with other nodes
+    pub key_file: PathBuf,               // libp2p identity, created on first run
+    pub verifier: VerifierConfig,        // Proof verification worker pool limits
+    pub extra_image_ids: Vec<[u32; 8]>,  // Other guest builds whose proofs we accept
+    pub proof_distribution: ProofDistribution,
+    pub lobby: Option<String>,           // Lobby to join at startup
+    pub bootstrap: Vec<Multiaddr>,       // DHT bootstrap peers, each ending in /p2p/<peer id>
+    pub mdns: bool,                      // Discover peers on the local network
+    pub lobby_lookup_interval: Duration, // How often lobby members are looked up in the DHT
 }
 
 // P2P node configuration
 pub struct P2PNode {
     pub peer_id: PeerId,
-    pub topic: IdentTopic,
+    keypair: Keypair, // Identity used for transport authentication and message signing
+    lobby: Option<String>, // Lobby whose topic carries our game traffic
+    discovery_topic: IdentTopic, // Lobby adverts from every node
     sender: mpsc::Sender<P2PMessage>,
     receiver: mpsc::Receiver<P2PMessage>,
-    known_peers: Vec<(String, u16)>, // List of known peers (hostname/IP, port)
-    connection_events: mpsc::Sender<String>, // Channel for connection events
+    listen_ip: IpAddr,
+    listen_port: u16,
+    known_peers: Vec<Multiaddr>, // Peers to dial at startup
+    bootstrap: Vec<Multiaddr>, // DHT bootstrap peers
+    mdns: bool,
+    lobby_lookup_interval: Duration,
+    connection_events: mpsc::Sender<ServerMessage>, // Channel for connection events
     node_name: String,
     custom_url: String, // Custom URL to share with other nodes
+    verifier_config: VerifierConfig, // Proof verification worker pool limits
+    compatible_image_ids: Vec<[u32; 8]>, // Guest builds whose proofs we accept, starting with our own
+    proof_distribution: ProofDistribution,
+    command_sender: mpsc::Sender<NodeCommand>,
+    command_receiver: mpsc::Receiver<NodeCommand>,
+}
+
+// Requests from the rest of the app to the running node
+pub enum NodeCommand {
+    JoinLobby(String),
+    LeaveLobby,
+    // Leave the lobby and stop; acknowledged once PlayerLeft is out
+    Shutdown(oneshot::Sender<()>),
+}
+
+// Handle for talking to a running P2P node
+#[derive(Clone)]
+pub struct P2PHandle {
+    pub sender: mpsc::Sender<P2PMessage>,
+    pub peer_id: PeerId,
+    commands: mpsc::Sender<NodeCommand>,
+}
+
+impl P2PHandle {
+    // Switch to another lobby; `name` must already be normalized
+    pub async fn join_lobby(&self, name: String) {
+        if self.commands.send(NodeCommand::JoinLobby(name)).await.is_err() {
+            eprintln!("P2P node is not running");
+        }
+    }
+
+    pub async fn leave_lobby(&self) {
+        if self.commands.send(NodeCommand::LeaveLobby).await.is_err() {
+            eprintln!("P2P node is not running");
+        }
+    }
+
+    // Tell peers we're leaving and stop the node, waiting briefly for it to finish
+    pub async fn shutdown(&self) {
+        let (done_tx, done_rx) = oneshot::channel();
+        if self.commands.send(NodeCommand::Shutdown(done_tx)).await.is_err() {
+            return;
+        }
+        if tokio::time::timeout(Duration::from_secs(3), done_rx).await.is_err() {
+            eprintln!("Timed out waiting for the P2P node to shut down");
+        }
+    }
 }
 
 impl P2PNode {
     // Create a new P2P node
     pub fn new(
-        topic_name: &str, 
-        known_peers: Vec<(String, u16)>, 
-        connection_events: mpsc::Sender<String>,
-        node_name: String,
-        custom_url: String,
+        keypair: Keypair,
+        connection_events: mpsc::Sender<ServerMessage>,
+        config: P2PConfig,
     ) -> Result<Self, Box<dyn Error>> {
-        // Create a random keypair for identity
-        let id_keys = Keypair::generate_ed25519();
-        let peer_id = PeerId::from(id_keys.public());
+        // Derive the peer ID from the node's identity
+        let peer_id = PeerId::from(keypair.public());
         println!("Local peer ID: {}", peer_id);
 
         // Create a channel for sending messages to the P2P network
         let (sender, receiver) = mpsc::channel(100);
+        let (command_sender, command_receiver) = mpsc::channel(16);
+
+        // Game traffic goes to the lobby's topic once we join one
+        let discovery_topic = IdentTopic::new(lobby::DISCOVERY_TOPIC);
 
-        // Create the gossipsub topic
-        let topic = IdentTopic::new(topic_name);
+        // Only proofs of our own guest build, or ones explicitly allowed, are verified
+        let mut compatible_image_ids = vec![FOOTSTEPS_GUEST_ID];
+        compatible_image_ids.extend(config.extra_image_ids);
+        println!("Local guest image ID: {}", image_id_hex(&FOOTSTEPS_GUEST_ID));
 
         Ok(Self {
             peer_id,
-            topic,
+            keypair,
+            lobby: config.lobby,
+            discovery_topic,
             sender,
             receiver,
-            known_peers,
+            listen_ip: config.listen_ip,
+            listen_port: config.listen_port,
+            known_peers: config.known_peers,
+            bootstrap: config.bootstrap,
+            mdns: config.mdns,
+            lobby_lookup_interval: config.lobby_lookup_interval,
             connection_events,
-            node_name,
-            custom_url,
+            node_name: config.node_name,
+            custom_url: config.custom_url,
+            verifier_config: config.verifier,
+            compatible_image_ids,
+            proof_distribution: config.proof_distribution,
+            command_sender,
+            command_receiver,
         })
     }
 
@@ -96,89 +483,239 @@ impl P2PNode {
         self.sender.clone()
     }
 
+    // Get a handle for sending messages to the node and stopping it
+    pub fn handle(&self) -> P2PHandle {
+        P2PHandle {
+            sender: self.sender(),
+            peer_id: self.peer_id,
+            commands: self.command_sender.clone(),
+        }
+    }
+
     // Start the P2P node
     pub async fn start(
         mut self,
         game_state: Arc<Mutex<GameState>>,
-        listen_port: u16,
     ) -> Result<(), Box<dyn Error>> {
-        // Create a simple TCP transport
-        let transport = tcp::tokio::Transport::new(tcp::Config::default())
+        // TCP with noise and yamux, alongside QUIC which brings its own encryption and multiplexing
+        let tcp_transport = tcp::tokio::Transport::new(tcp::Config::default())
             .upgrade(upgrade::Version::V1)
-            .authenticate(noise::Config::new(&Keypair::generate_ed25519())?)
-            .multiplex(yamux::Config::default())
+            .authenticate(noise::Config::new(&self.keypair)?)
+            .multiplex(yamux::Config::default());
+        let quic_transport = quic::tokio::Transport::new(quic::Config::new(&self.keypair));
+        // Resolve /dns, /dns4 and /dns6 addresses with the system resolver
+        let transport = dns::tokio::Transport::system(OrTransport::new(quic_transport, tcp_transport))?
+            .map(|output, _| match output {
+                Either::Left((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+                Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+            })
             .boxed();
 
         // Create the gossipsub behavior
         let gossipsub_config = gossipsub::ConfigBuilder::default()
             .heartbeat_interval(Duration::from_secs(10))
             .validation_mode(gossipsub::ValidationMode::Strict)
-            .max_transmit_size(1024 * 1024)
+            .validate_messages()
+            .max_transmit_size(MAX_TRANSMIT_SIZE)
             .build()?;
 
         let mut gossipsub = gossipsub::Behaviour::new(
-            MessageAuthenticity::Signed(Keypair::generate_ed25519()),
+            MessageAuthenticity::Signed(self.keypair.clone()),
             gossipsub_config,
         )?;
 
-        // Subscribe to the topic
-        gossipsub.subscribe(&self.topic)?;
+        // Score peers so repeat offenders get graylisted
+        let (score_params, score_thresholds) = peer_score_config();
+        gossipsub.with_peer_score(score_params, score_thresholds)?;
+        gossipsub.set_topic_params(self.discovery_topic.clone(), topic_score_params())?;
+
+        // Subscribe to lobby adverts; the lobby topic itself is joined below
+        gossipsub.subscribe(&self.discovery_topic)?;
 
-        // Create the mdns behavior for local peer discovery
-        let mdns = MdnsBehaviour::new(mdns::Config::default(), self.peer_id)?;
+        // Create the mdns behavior for local peer discovery. It can be turned off,
+        // e.g. to check that local nodes find each other through the DHT alone.
+        let mdns = if self.mdns {
+            Some(MdnsBehaviour::new(mdns::Config::default(), self.peer_id)?)
+        } else {
+            None
+        };
+
+        // Kademlia finds peers and lobbies beyond the local network
+        let mut kad_config = kad::Config::default();
+        kad_config.set_protocol_names(vec![KAD_PROTOCOL]);
+        let mut kad = kad::Behaviour::with_config(self.peer_id, MemoryStore::new(self.peer_id), kad_config);
+        // Every player serves the DHT, even without a confirmed external address
+        kad.set_mode(Some(kad::Mode::Server));
+        for addr in &self.bootstrap {
+            if let Some(Protocol::P2p(peer_id)) = addr.iter().last() {
+                kad.add_address(&peer_id, addr.clone());
+            }
+        }
+
+        // Identify tells us the addresses peers listen on, which feed the DHT routing table
+        let identify = identify::Behaviour::new(identify::Config::new(
+            IDENTIFY_PROTOCOL.to_string(),
+            self.keypair.public(),
+        ));
+
+        // Full receipts are pulled from peers on demand over their own protocol
+        let receipts = request_response::cbor::Behaviour::new(
+            [(receipts::PROTOCOL, ProtocolSupport::Full)],
+            request_response::Config::default(),
+        );
 
         // Build the swarm
         let mut swarm = SwarmBuilder::with_tokio_executor(
             transport,
-            GameBehaviour { gossipsub, mdns },
+            GameBehaviour { gossipsub, mdns: mdns.into(), kad, identify, receipts },
             self.peer_id,
         )
         .build();
 
-        // Listen on all interfaces and the specified port
-        let listen_addr = format!("/ip4/0.0.0.0/tcp/{}", listen_port);
-        println!("Attempting to listen on {}", listen_addr);
-        swarm.listen_on(listen_addr.parse()?)?;
+        // Join the startup lobby, if any
+        if let Some(name) = self.lobby.take() {
+            self.join_lobby(&mut swarm, &game_state, name)?;
+        }
 
-        // Connect to known peers
-        for (peer_host, peer_port) in &self.known_peers {
-            let peer_addr = format!("/ip4/{}/tcp/{}", peer_host, peer_port);
-            println!("Attempting to connect to peer at {}", peer_addr);
+        // Listen on the configured address and port, over both TCP and QUIC
+        let listen_ip = Multiaddr::from(self.listen_ip);
+        for listen_addr in [
+            listen_ip.clone().with(Protocol::Tcp(self.listen_port)),
+            listen_ip.with(Protocol::Udp(self.listen_port)).with(Protocol::QuicV1),
+        ] {
+            println!("Attempting to listen on {}", listen_addr);
+            swarm.listen_on(listen_addr)?;
+        }
 
-            match peer_addr.parse::<Multiaddr>() {
-                Ok(addr) => {
-                    if let Err(e) = swarm.dial(addr.clone()) {
-                        eprintln!("Failed to dial {}: {:?}", addr, e);
-                    } else {
-                        println!("Dialing peer at {}", addr);
-                    }
-                }
-                Err(e) => eprintln!("Invalid multiaddr {}: {:?}", peer_addr, e),
+        // Connect to known peers. Those given with a peer ID can also seed the DHT.
+        for addr in &self.known_peers {
+            if let Some(Protocol::P2p(peer_id)) = addr.iter().last() {
+                swarm.behaviour_mut().kad.add_address(&peer_id, addr.clone());
+            }
+            println!("Attempting to connect to peer at {}", addr);
+            if let Err(e) = swarm.dial(addr.clone()) {
+                eprintln!("Failed to dial {}: {:?}", addr, e);
+            } else {
+                println!("Dialing peer at {}", addr);
             }
         }
 
-        // Remove the periodic node info interval
-        // let mut node_info_interval = tokio::time::interval(Duration::from_secs(10));
-        
+        // Connect to the DHT through the bootstrap peers
+        for addr in &self.bootstrap {
+            if let Err(e) = swarm.dial(addr.clone()) {
+                eprintln!("Failed to dial bootstrap peer {}: {:?}", addr, e);
+            }
+        }
+        if !self.bootstrap.is_empty() {
+            if let Err(e) = swarm.behaviour_mut().kad.bootstrap() {
+                eprintln!("Failed to bootstrap the DHT: {:?}", e);
+            }
+        }
+        let mut lookup_timer = tokio::time::interval(self.lobby_lookup_interval);
+
+        // Proofs are verified on a blocking worker pool; results come back on this channel
+        let (mut verifier, mut verify_results) = VerifierPool::new(self.verifier_config);
+
+        // Receipts we proved or verified, served to peers that ask for them
+        let mut receipt_cache = ReceiptCache::default();
+        let mut pending_fetches: HashMap<request_response::RequestId, PendingFetch> = HashMap::new();
+
+        // Chunked proofs being reassembled, with the gossip messages they arrived in
+        let mut reassembler: Reassembler<(MessageId, PeerId)> = Reassembler::new(CHUNK_TRANSFER_TIMEOUT);
+        let mut chunk_timer = tokio::time::interval(Duration::from_secs(5));
+
         // Flag to track if we should try sending node info
         let mut try_node_info = true; // Start with true to send node info once at startup
         let mut retry_timer = tokio::time::interval(Duration::from_secs(3));
+
+        // PlayerJoined goes out once, alongside our first node info
+        let mut joined = false;
+        let mut heartbeat_timer = tokio::time::interval(HEARTBEAT_INTERVAL);
         
         // Event loop
         loop {
             tokio::select! {
-                // Remove the periodic node info broadcast
-                // _ = node_info_interval.tick() => {
-                //     // Periodically broadcast our node info
-                //     self.broadcast_node_info(&mut swarm);
-                // }
+                _ = heartbeat_timer.tick() => {
+                    let heartbeat = P2PMessage::Heartbeat { player_id: self.peer_id.to_string() };
+                    self.publish(&mut swarm, &heartbeat);
+                    self.advertise_lobby(&mut swarm, &game_state);
+
+                    let mut state = game_state.lock().unwrap();
+                    for player_id in state.expire_players(PLAYER_TIMEOUT) {
+                        println!("Player {} timed out", player_id);
+                    }
+                    state.expire_lobbies(PLAYER_TIMEOUT);
+                }
+                Some(command) = self.command_receiver.recv() => match command {
+                    NodeCommand::JoinLobby(name) => {
+                        if let Err(e) = self.join_lobby(&mut swarm, &game_state, name) {
+                            eprintln!("Error joining lobby: {}", e);
+                            continue;
+                        }
+                        self.advertise_lobby(&mut swarm, &game_state);
+                        // Introduce ourselves to the new lobby
+                        joined = false;
+                        try_node_info = true;
+                    }
+                    NodeCommand::LeaveLobby => {
+                        self.leave_lobby(&mut swarm, &game_state);
+                    }
+                    NodeCommand::Shutdown(done) => {
+                        println!("Announcing that we're leaving");
+                        self.leave_lobby(&mut swarm, &game_state);
+
+                        // Keep the swarm running for a moment so the message reaches our peers
+                        let flush = tokio::time::sleep(Duration::from_millis(500));
+                        tokio::pin!(flush);
+                        loop {
+                            tokio::select! {
+                                _ = &mut flush => break,
+                                _ = swarm.select_next_some() => {}
+                            }
+                        }
+                        let _ = done.send(());
+                        return Ok(());
+                    }
+                },
+                _ = lookup_timer.tick() => {
+                    // Look for lobby members that joined after us
+                    if let Some(name) = &self.lobby {
+                        swarm.behaviour_mut().kad.get_providers(lobby::dht_key(name));
+                    }
+                }
+                _ = chunk_timer.tick() => {
+                    for (player_id, held) in reassembler.expire() {
+                        println!("Proof transfer from {} failed: {}", player_id, ChunkFailure::TimedOut);
+                        game_state.lock().unwrap().remote_player(&player_id).status =
+                            format!("Proof transfer failed: {}", ChunkFailure::TimedOut);
+                        for (id, source) in &held {
+                            report_validation(&mut swarm, id, source, MessageAcceptance::Ignore);
+                        }
+                    }
+                }
                 _ = retry_timer.tick(), if try_node_info => {
+                    // Nobody to introduce ourselves to outside a lobby
+                    if self.lobby.is_none() {
+                        try_node_info = false;
+                        continue;
+                    }
+
                     // Try to send node info after startup or new connection
                     println!("Trying to send node info...");
                     if self.broadcast_node_info(&mut swarm) {
                         // If successful, reset the flag
                         try_node_info = false;
                         println!("Successfully sent node info");
+
+                        if !joined {
+                            let joined_msg = P2PMessage::PlayerJoined {
+                                player_id: self.peer_id.to_string(),
+                                name: self.node_name.clone(),
+                            };
+                            joined = self.publish(&mut swarm, &joined_msg);
+                            // Retry until the join announcement is out as well
+                            try_node_info = !joined;
+                        }
                     } else {
                         println!("Failed to send node info, will retry in 3 seconds");
                     }
@@ -193,16 +730,12 @@ impl P2PNode {
                             println!("Connection established with {} via {}", peer_id, addr);
                             
                             // Send connection event to the main thread with more detailed information
-                            let event_data = serde_json::json!({
-                                "peer_id": peer_id.to_string(),
-                                "address": addr.to_string(),
-                                "is_incoming": endpoint.is_listener(),
-                                "timestamp": std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
-                            });
-                            
-                            let event_msg = serde_json::to_string(&event_data).unwrap_or_else(|_| 
-                                format!("Connected to peer: {} via {}", peer_id, addr)
-                            );
+                            let event_msg = ServerMessage::P2pConnection {
+                                peer_id: peer_id.to_string(),
+                                address: addr.to_string(),
+                                is_incoming: endpoint.is_listener(),
+                                timestamp: unix_now(),
+                            };
                             
                             if let Err(e) = self.connection_events.send(event_msg).await {
                                 eprintln!("Failed to send connection event: {:?}", e);
@@ -222,108 +755,307 @@ impl P2PNode {
                             GameBehaviourEvent::Mdns(mdns::Event::Discovered(list)) => {
                                 for (peer_id, multiaddr) in list {
                                     println!("mDNS discovered peer: {} at {}", peer_id, multiaddr);
-                                    swarm.dial(multiaddr)?;
+                                    swarm.behaviour_mut().kad.add_address(&peer_id, multiaddr.clone());
+                                    if let Err(e) = swarm.dial(multiaddr) {
+                                        eprintln!("Failed to dial mDNS peer {}: {:?}", peer_id, e);
+                                    }
                                 }
                             }
+                            GameBehaviourEvent::Identify(identify::Event::Received { peer_id, info }) => {
+                                // Only peers speaking our DHT protocol belong in the routing table
+                                if info.protocols.contains(&KAD_PROTOCOL) {
+                                    for addr in info.listen_addrs {
+                                        swarm.behaviour_mut().kad.add_address(&peer_id, addr);
+                                    }
+                                }
+                            }
+                            GameBehaviourEvent::Kad(kad::Event::OutboundQueryProgressed { result, .. }) => match result {
+                                kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+                                    // Other members of our lobby; gossipsub takes over once we're connected
+                                    for provider in providers {
+                                        if provider != self.peer_id && !swarm.is_connected(&provider) {
+                                            println!("Found lobby member {} in the DHT", provider);
+                                            if let Err(e) = swarm.dial(provider) {
+                                                eprintln!("Failed to dial lobby member {}: {:?}", provider, e);
+                                            }
+                                        }
+                                    }
+                                }
+                                kad::QueryResult::GetProviders(Err(e)) => {
+                                    println!("Lobby lookup failed: {:?}", e);
+                                }
+                                kad::QueryResult::StartProviding(Err(e)) => {
+                                    eprintln!("Failed to publish lobby record: {:?}", e);
+                                }
+                                kad::QueryResult::Bootstrap(Ok(_)) => {
+                                    println!("DHT bootstrap step complete");
+                                }
+                                kad::QueryResult::Bootstrap(Err(e)) => {
+                                    eprintln!("DHT bootstrap failed: {:?}", e);
+                                }
+                                _ => {}
+                            },
                             GameBehaviourEvent::Gossipsub(gossipsub::Event::Message {
                                 propagation_source: peer_id,
-                                message_id: _,
+                                message_id,
                                 message,
                             }) => {
-                                println!("Received proof from {}", peer_id);
-
-                                // Try to parse the message
-                                if let Ok(p2p_msg) = serde_json::from_slice::<P2PMessage>(&message.data) {
-                                    match &p2p_msg {
-                                        P2PMessage::Proof { player_id, receipt, ImageID } => {
-                                            println!("Proof from {}. ImageID: {:?}", player_id, ImageID);
-
-                                            // Here you would verify the proof and update the game state
-                                            // For now, just update the position if it's not from this node
-                                            if !player_id.starts_with(&self.node_name) {
-                                                // Only update if it's from another player
-                                                // In a real implementation, you'd verify the proof first
-
-                                                println!("Verifying proof...");
-                                                {
-                                                    let mut state = game_state.lock().unwrap();
-                                                    state.proof_status = "Verifying proof...".to_string();
-                                                }
-
-                                                // Verify the proof
-                                                if let Err(e) = receipt.verify(*ImageID) {
-                                                    println!("Error verifying proof: {:?}", e);
+                                println!("Received message via {}", peer_id);
 
-                                                    // Mark as no longer processing
-                                                    let mut state = game_state.lock().unwrap();
-                                                    state.proof_status = "Proof verification failed".to_string();
+                                // Decode the message and check it was signed by the identity it claims.
+                                // Forged and undecodable messages are rejected so the relaying peer loses score.
+                                let p2p_msg = match authenticate_message(&self.peer_id, &message) {
+                                    Ok(p2p_msg) => p2p_msg,
+                                    Err(rejection) => {
+                                        println!("Dropping message relayed by {}: {}", peer_id, rejection);
+                                        let acceptance = match rejection {
+                                            MessageRejection::OwnMessage => MessageAcceptance::Ignore,
+                                            _ => MessageAcceptance::Reject,
+                                        };
+                                        report_validation(&mut swarm, &message_id, &peer_id, acceptance);
+                                        continue;
+                                    }
+                                };
 
-                                                    continue;
-                                                }
+                                // Lobby adverts belong on the discovery topic and everything else on our lobby's
+                                let on_discovery = message.topic == self.discovery_topic.hash();
+                                if on_discovery != matches!(p2p_msg, P2PMessage::LobbyAdvert { .. }) {
+                                    println!("Ignoring message from {} sent to the wrong topic", p2p_msg.claimed_id());
+                                    report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Ignore);
+                                    continue;
+                                }
 
-                                                println!("Proof verified successfully!");
+                                // Any message signed by a player in our lobby shows they're still around
+                                if !on_discovery {
+                                    game_state.lock().unwrap().mark_seen(p2p_msg.claimed_id());
+                                }
 
-                                                // Extract the outputs
-                                                let outputs: Outputs = match receipt.journal.decode() {
-                                                    Ok(outputs) => outputs,
-                                                    Err(e) => {
-                                                        println!("Error decoding journal: {:?}", e);
+                                // Proofs are only propagated once verified; everything else can go on right away
+                                if !matches!(
+                                    p2p_msg,
+                                    P2PMessage::Proof { .. } | P2PMessage::ProofAnnouncement { .. } | P2PMessage::ProofChunk { .. }
+                                ) {
+                                    report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Accept);
+                                }
 
-                                                        // Mark as no longer processing
-                                                        let mut state = game_state.lock().unwrap();
-                                                        state.proof_status = "Journal decoding failed".to_string();
+                                match p2p_msg {
+                                    P2PMessage::Proof { player_id, receipt, ImageID } => {
+                                        println!("Proof from {}. ImageID: {}", player_id, image_id_hex(&ImageID));
 
-                                                        continue;
-                                                    }
-                                                };
-
-                                                // Update game state
-                                                let mut state: std::sync::MutexGuard<'_, GameState> = game_state.lock().unwrap();
+                                        // The sender picks the ImageID, so never verify against one we don't know;
+                                        // otherwise a proof of any program at all would be accepted
+                                        if !self.compatible_image_ids.contains(&ImageID) {
+                                            println!("Ignoring proof from {}: incompatible guest image ID", player_id);
+                                            mark_incompatible(&game_state, &player_id);
+                                            report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Ignore);
+                                            continue;
+                                        }
 
-                                                // Get the trail length before moving it
-                                                let trail_len = outputs.trail_positions.len();
-                                                let trail_summary = format!("{:?}", outputs.trail_positions);
+                                        // player_id is the authenticated PeerId of the prover
+                                        let job = VerifyJob {
+                                            player_id,
+                                            receipt,
+                                            image_id: ImageID,
+                                            messages: vec![(message_id, peer_id)],
+                                        };
+                                        queue_verification(&mut swarm, &game_state, &mut verifier, job);
+                                    }
+                                    P2PMessage::ProofAnnouncement { player_id, epoch, sequence, journal_digest, receipt_hash, ImageID } => {
+                                        println!("Proof {} of epoch {} announced by {}. ImageID: {}", sequence, epoch, player_id, image_id_hex(&ImageID));
 
-                                                // Update the verified trail - make a deep copy to ensure it's a new object
-                                                state.verified_trail = outputs.trail_positions.clone();
-                                                state.proof_status = format!("Proof verified! Trail: {} positions", trail_len);
+                                        if !self.compatible_image_ids.contains(&ImageID) {
+                                            println!("Ignoring proof from {}: incompatible guest image ID", player_id);
+                                            mark_incompatible(&game_state, &player_id);
+                                            report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Ignore);
+                                            continue;
+                                        }
 
-                                                println!("Batch processed! Trail verified with {} positions: {}",
-                                                        trail_len, trail_summary);
+                                        // Already verified through another announcement
+                                        if receipt_cache.contains(&receipt_hash) {
+                                            report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Ignore);
+                                            continue;
+                                        }
 
-                                                // Force immediate update of the trail
-                                                drop(state); // Release the lock before sleeping
+                                        // Batches at or behind the player's chain aren't worth fetching
+                                        let sequence_check = {
+                                            let state = game_state.lock().unwrap();
+                                            check_sequence(state.remote_players.get(&player_id), epoch, sequence)
+                                        };
+                                        if let Err(e @ (ChainError::Replay | ChainError::StaleEpoch)) = sequence_check {
+                                            println!("Ignoring announced proof {} of epoch {} from {}: {}", sequence, epoch, player_id, e);
+                                            report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Ignore);
+                                            continue;
+                                        }
 
-                                                // Small delay to ensure the trail update is processed
-                                                thread::sleep(Duration::from_millis(50));
+                                        // The relaying peer verified the receipt before forwarding the
+                                        // announcement, so it can serve it; the prover always can
+                                        let prover = player_id.parse::<PeerId>().ok().filter(|prover| *prover != peer_id);
+                                        game_state.lock().unwrap().remote_player(&player_id).status = "Fetching receipt".to_string();
+                                        let fetch = PendingFetch {
+                                            player_id,
+                                            image_id: ImageID,
+                                            request: ReceiptRequest::Hash(receipt_hash),
+                                            journal_digest: Some(journal_digest),
+                                            messages: vec![(message_id, peer_id)],
+                                            fallback: prover,
+                                        };
+                                        request_receipt(&mut swarm, &mut pending_fetches, peer_id, fetch);
+                                    }
+                                    P2PMessage::ProofChunk { player_id, content_hash, index, total, data } => {
+                                        let tag = (message_id.clone(), peer_id);
+                                        match reassembler.add(&player_id, content_hash, index, total, data, tag) {
+                                            ChunkEvent::Partial { received, total } => {
+                                                println!("Received chunk {} of a proof from {} ({}/{})", index, player_id, received, total);
+                                                game_state.lock().unwrap().remote_player(&player_id).status =
+                                                    format!("Receiving proof: {}/{} chunks", received, total);
+                                            }
+                                            ChunkEvent::Complete { data, held } => {
+                                                println!("Reassembled a {} byte proof from {}", data.len(), player_id);
+                                                // Every chunk is held back until the whole proof is verified,
+                                                // so a bad proof's chunks never propagate
+                                                match codec::decode(&data) {
+                                                    Ok(P2PMessage::Proof { player_id: prover, receipt, ImageID }) if prover == player_id => {
+                                                        if !self.compatible_image_ids.contains(&ImageID) {
+                                                            println!("Ignoring proof from {}: incompatible guest image ID", player_id);
+                                                            mark_incompatible(&game_state, &player_id);
+                                                            for (id, source) in &held {
+                                                                report_validation(&mut swarm, id, source, MessageAcceptance::Ignore);
+                                                            }
+                                                            continue;
+                                                        }
+                                                        let job = VerifyJob {
+                                                            player_id,
+                                                            receipt,
+                                                            image_id: ImageID,
+                                                            messages: held,
+                                                        };
+                                                        queue_verification(&mut swarm, &game_state, &mut verifier, job);
+                                                    }
+                                                    _ => {
+                                                        println!("Rejecting chunked message from {}: not a proof by its sender", player_id);
+                                                        game_state.lock().unwrap().remote_player(&player_id).status =
+                                                            "Proof transfer failed: invalid payload".to_string();
+                                                        for (id, source) in &held {
+                                                            report_validation(&mut swarm, id, source, MessageAcceptance::Reject);
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                            ChunkEvent::Failed { reason, held } => {
+                                                println!("Proof transfer from {} failed: {}", player_id, reason);
+                                                game_state.lock().unwrap().remote_player(&player_id).status =
+                                                    format!("Proof transfer failed: {}", reason);
+                                                // Chunks that don't hash to what their signer claimed are forged
+                                                let acceptance = match reason {
+                                                    ChunkFailure::HashMismatch => MessageAcceptance::Reject,
+                                                    _ => MessageAcceptance::Ignore,
+                                                };
+                                                for (id, source) in &held {
+                                                    report_validation(&mut swarm, id, source, acceptance);
+                                                }
+                                            }
+                                            ChunkEvent::Duplicate => {
+                                                report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Ignore);
+                                            }
+                                            ChunkEvent::Invalid(e) => {
+                                                println!("Rejecting proof chunk from {}: {}", player_id, e);
+                                                report_validation(&mut swarm, &message_id, &peer_id, MessageAcceptance::Reject);
                                             }
                                         }
-                                        P2PMessage::PlayerJoined { player_id, name } => {
-                                            println!("Player joined: {} ({})", name, player_id);
+                                    }
+                                    P2PMessage::PlayerJoined { player_id, name } => {
+                                        println!("Player joined: {} ({})", name, player_id);
+                                        game_state.lock().unwrap().remote_player(&player_id).name = name;
+                                    }
+                                    P2PMessage::PlayerLeft { player_id } => {
+                                        println!("Player left: {}", player_id);
+                                        game_state.lock().unwrap().remote_player(&player_id).online = false;
+                                    }
+                                    P2PMessage::BatchCut { player_id, epoch, sequence, beacon } => {
+                                        record_cut(&mut game_state.lock().unwrap(), &player_id, epoch, sequence, beacon);
+                                    }
+                                    P2PMessage::Heartbeat { .. } => {}
+                                    P2PMessage::LobbyAdvert { node_id, lobby, rules, map_hash } => {
+                                        game_state.lock().unwrap().record_lobby(lobby.as_deref(), &node_id, rules, map_hash);
+                                    }
+                                    P2PMessage::NodeInfo { node_id, name, custom_url } => {
+                                        println!("Received node info from {}: name={}, url={}", node_id, name, custom_url);
+                                        {
+                                            let mut state = game_state.lock().unwrap();
+                                            let player = state.remote_player(&node_id);
+                                            player.name = name.clone();
+                                            player.custom_url = custom_url.clone();
                                         }
-                                        P2PMessage::PlayerLeft { player_id } => {
-                                            println!("Player left: {}", player_id);
+                                        
+                                        // Send the node info to the main thread
+                                        let node_info_msg = ServerMessage::NodeInfo {
+                                            peer_id: node_id,
+                                            name,
+                                            custom_url,
+                                        };
+                                        
+                                        if let Err(e) = self.connection_events.send(node_info_msg).await {
+                                            eprintln!("Failed to send node info event: {:?}", e);
                                         }
-                                        P2PMessage::NodeInfo { node_id, name, custom_url } => {
-                                            println!("Received node info from {}: name={}, url={}", node_id, name, custom_url);
-                                            
-                                            // Send the node info to the main thread
-                                            let node_info_data = serde_json::json!({
-                                                "type": "node_info",
-                                                "peer_id": node_id,
-                                                "name": name,
-                                                "custom_url": custom_url,
-                                            });
-                                            
-                                            let node_info_msg = serde_json::to_string(&node_info_data).unwrap_or_else(|_| 
-                                                format!("Node info: {} ({}), URL: {}", name, node_id, custom_url)
-                                            );
-                                            
-                                            if let Err(e) = self.connection_events.send(node_info_msg).await {
-                                                eprintln!("Failed to send node info event: {:?}", e);
-                                            }
+                                    }
+                                }
+                            }
+                            GameBehaviourEvent::Receipts(request_response::Event::Message { peer, message }) => match message {
+                                request_response::Message::Request { request, channel, .. } => {
+                                    let receipt = match &request {
+                                        ReceiptRequest::Hash(hash) => receipt_cache.get(hash),
+                                        ReceiptRequest::Batch(batch) => receipt_cache.get_batch(batch),
+                                    };
+                                    let response = match receipt {
+                                        Some(receipt) => ReceiptResponse::Found(receipt.clone()),
+                                        None => ReceiptResponse::NotFound,
+                                    };
+                                    if swarm.behaviour_mut().receipts.send_response(channel, response).is_err() {
+                                        eprintln!("Failed to send receipt to {}: connection closed", peer);
+                                    }
+                                }
+                                request_response::Message::Response { request_id, response } => {
+                                    let Some(fetch) = pending_fetches.remove(&request_id) else {
+                                        continue;
+                                    };
+                                    let receipt = match response {
+                                        ReceiptResponse::Found(receipt) => receipt,
+                                        ReceiptResponse::NotFound => {
+                                            println!("{} does not have {}", peer, fetch.request);
+                                            retry_fetch(&mut swarm, &game_state, &mut pending_fetches, fetch);
+                                            continue;
                                         }
+                                    };
+
+                                    // A receipt other than the one we asked for is the serving peer's fault,
+                                    // so try elsewhere. One that hashes right but whose journal doesn't match
+                                    // means the announcement itself was bogus.
+                                    if !serves(&fetch.request, &receipt) {
+                                        println!("{} served a receipt other than {}", peer, fetch.request);
+                                        retry_fetch(&mut swarm, &game_state, &mut pending_fetches, fetch);
+                                        continue;
                                     }
+                                    if fetch.journal_digest.is_some_and(|digest| digest != journal_digest(&receipt.journal.bytes)) {
+                                        println!("Rejecting announcement from {}: journal digest does not match its receipt", fetch.player_id);
+                                        for (id, source) in &fetch.messages {
+                                            report_validation(&mut swarm, id, source, MessageAcceptance::Reject);
+                                        }
+                                        continue;
+                                    }
+
+                                    let job = VerifyJob {
+                                        player_id: fetch.player_id,
+                                        receipt,
+                                        image_id: fetch.image_id,
+                                        messages: fetch.messages,
+                                    };
+                                    queue_verification(&mut swarm, &game_state, &mut verifier, job);
+                                }
+                            },
+                            GameBehaviourEvent::Receipts(request_response::Event::OutboundFailure { peer, request_id, error }) => {
+                                if let Some(fetch) = pending_fetches.remove(&request_id) {
+                                    eprintln!("Failed to fetch receipt from {}: {:?}", peer, error);
+                                    retry_fetch(&mut swarm, &game_state, &mut pending_fetches, fetch);
                                 }
                             }
                             _ => {}
@@ -331,14 +1063,135 @@ impl P2PNode {
                         _ => {}
                     }
                 }
+                Some(outcome) = verify_results.recv() => {
+                    verifier.complete(&outcome.player_id);
+                    let player_id = outcome.player_id;
+                    let batch = outcome.result.as_ref().ok().map(|outputs| BatchId {
+                        player_id: player_id.clone(),
+                        epoch: outputs.epoch,
+                        sequence: outputs.sequence,
+                    });
+
+                    // Invalid receipts and journals are rejected, which lowers the relaying peer's score
+                    let acceptance = match outcome.result {
+                        Ok(outputs) => {
+                            println!("Proof from {} verified successfully!", player_id);
+                            match apply_verified_proof(&game_state, &player_id, outputs, outcome.journal_digest) {
+                                Ok(()) => MessageAcceptance::Accept,
+                                Err(ChainError::Gap { epoch, from, to }) => {
+                                    // Whoever relayed this batch likely has the ones before it
+                                    let relay = outcome.messages.first().map(|(_, source)| *source);
+                                    let missing = MissingBatches {
+                                        player_id: &player_id,
+                                        image_id: outcome.image_id,
+                                        epoch,
+                                        sequences: from..=to,
+                                    };
+                                    fetch_missing(&mut swarm, &mut pending_fetches, missing, relay);
+                                    MessageAcceptance::Ignore
+                                }
+                                Err(ChainError::UnknownBeacon(beacon)) => {
+                                    // The player accepted the beacon batch, so it can serve it
+                                    let prover = player_id.parse::<PeerId>().ok();
+                                    let missing = MissingBatches {
+                                        player_id: &beacon.player_id,
+                                        image_id: outcome.image_id,
+                                        epoch: beacon.epoch,
+                                        sequences: beacon.sequence..=beacon.sequence,
+                                    };
+                                    fetch_missing(&mut swarm, &mut pending_fetches, missing, prover);
+                                    MessageAcceptance::Ignore
+                                }
+                                // Someone else's receipt passed off under this name
+                                Err(ChainError::WrongPlayer) => MessageAcceptance::Reject,
+                                Err(_) => MessageAcceptance::Ignore,
+                            }
+                        }
+                        Err(VerifyError::Receipt(e)) => {
+                            println!("Error verifying proof from {}: {}", player_id, e);
+                            let mut state = game_state.lock().unwrap();
+                            state.set_proof_status("Proof verification failed");
+                            state.remote_player(&player_id).status = "Proof verification failed".to_string();
+                            MessageAcceptance::Reject
+                        }
+                        Err(VerifyError::Journal(e)) => {
+                            println!("Error decoding journal from {}: {}", player_id, e);
+                            let mut state = game_state.lock().unwrap();
+                            state.set_proof_status("Journal decoding failed");
+                            state.remote_player(&player_id).status = "Journal decoding failed".to_string();
+                            MessageAcceptance::Reject
+                        }
+                        Err(VerifyError::DevReceipt) => {
+                            println!("Rejecting dev-mode receipt from {}", player_id);
+                            let mut state = game_state.lock().unwrap();
+                            state.set_proof_status("Proof rejected: dev-mode receipt");
+                            state.remote_player(&player_id).status = "Proof rejected: dev-mode receipt".to_string();
+                            MessageAcceptance::Reject
+                        }
+                    };
+
+                    // Accepted dev receipts stay flagged, since they prove nothing
+                    if matches!(acceptance, MessageAcceptance::Accept) && matches!(outcome.receipt.inner, InnerReceipt::Fake(_)) {
+                        let mut state = game_state.lock().unwrap();
+                        let player = state.remote_player(&player_id);
+                        player.status = format!("{} (dev mode, not proven)", player.status);
+                    }
+
+                    // Keep accepted receipts so we can serve them to peers that fetch them from us
+                    if let (MessageAcceptance::Accept, Some(batch)) = (acceptance, batch) {
+                        match receipts::receipt_hash(&outcome.receipt) {
+                            Ok(hash) => receipt_cache.insert(hash, batch, outcome.receipt),
+                            Err(e) => eprintln!("Error hashing receipt from {}: {:?}", player_id, e),
+                        }
+                    }
+                    for (id, source) in &outcome.messages {
+                        report_validation(&mut swarm, id, source, acceptance);
+                    }
+                }
                 Some(msg) = self.receiver.recv() => {
                     // Received a message to send to the P2P network
                     println!("Sending message to P2P network");
 
-                    // Serialize and publish the message
-                    match serde_json::to_vec(&msg) {
+                    // We serve our own receipts to peers missing them. In announce mode only a
+                    // small announcement is gossiped and every peer fetches the receipt.
+                    let msg = match msg {
+                        P2PMessage::Proof { player_id, receipt, ImageID } => {
+                            match announce_proof(&mut receipt_cache, &player_id, &receipt, ImageID) {
+                                Ok(announcement) if self.proof_distribution == ProofDistribution::Announce => announcement,
+                                Ok(_) => P2PMessage::Proof { player_id, receipt, ImageID },
+                                Err(e) => {
+                                    eprintln!("Error announcing proof: {}", e);
+                                    continue;
+                                }
+                            }
+                        }
+                        msg => msg,
+                    };
+
+                    let Some(topic) = self.lobby_topic() else {
+                        println!("Not in a lobby, message not published");
+                        continue;
+                    };
+
+                    // Serialize and publish the message, in chunks if it's a proof too large for one message
+                    match codec::encode(&msg) {
+                        Ok(data) if data.len() > MAX_TRANSMIT_SIZE - GOSSIP_OVERHEAD && matches!(msg, P2PMessage::Proof { .. }) => {
+                            let parts = chunks::split(msg.claimed_id(), &data);
+                            println!("Publishing {} byte proof in {} chunks", data.len(), parts.len());
+                            for chunk in &parts {
+                                let published = codec::encode(chunk)
+                                    .map_err(|e| e.to_string())
+                                    .and_then(|chunk| {
+                                        swarm.behaviour_mut().gossipsub.publish(topic.clone(), chunk).map_err(|e| e.to_string())
+                                    });
+                                if let Err(e) = published {
+                                    eprintln!("Error publishing proof chunk: {}", e);
+                                    break;
+                                }
+                            }
+                        }
                         Ok(data) => {
-                            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), data) {
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic, data) {
                                 eprintln!("Error publishing message: {:?}", e);
                             }
                         }
@@ -351,8 +1204,103 @@ impl P2PNode {
         }
     }
     
+    // Topic of the lobby we're in
+    fn lobby_topic(&self) -> Option<IdentTopic> {
+        self.lobby.as_deref().map(lobby::topic)
+    }
+
+    // Leave our current lobby and subscribe to another one's topic
+    fn join_lobby(
+        &mut self,
+        swarm: &mut libp2p::swarm::Swarm<GameBehaviour>,
+        game_state: &Arc<Mutex<GameState>>,
+        name: String,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.lobby.as_ref() == Some(&name) {
+            return Ok(());
+        }
+        self.leave_lobby(swarm, game_state);
+
+        let topic = lobby::topic(&name);
+        let gossipsub = &mut swarm.behaviour_mut().gossipsub;
+        gossipsub.set_topic_params(topic.clone(), topic_score_params())?;
+        gossipsub.subscribe(&topic)?;
+        println!("Joined lobby {}", name);
+
+        // Publish our membership in the DHT and look for the other members
+        let kad = &mut swarm.behaviour_mut().kad;
+        kad.start_providing(lobby::dht_key(&name))?;
+        kad.get_providers(lobby::dht_key(&name));
+
+        game_state.lock().unwrap().set_lobby(Some(name.clone()));
+        self.lobby = Some(name);
+        Ok(())
+    }
+
+    // Say goodbye to our lobby and stop following its topic
+    fn leave_lobby(&mut self, swarm: &mut libp2p::swarm::Swarm<GameBehaviour>, game_state: &Arc<Mutex<GameState>>) {
+        let Some(topic) = self.lobby_topic() else {
+            return;
+        };
+        let left = P2PMessage::PlayerLeft { player_id: self.peer_id.to_string() };
+        self.publish(swarm, &left);
+        if let Err(e) = swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+            eprintln!("Error leaving lobby topic: {:?}", e);
+        }
+        if let Some(name) = &self.lobby {
+            swarm.behaviour_mut().kad.stop_providing(&lobby::dht_key(name));
+        }
+        println!("Left lobby {}", self.lobby.take().unwrap_or_default());
+
+        game_state.lock().unwrap().set_lobby(None);
+        self.advertise_lobby(swarm, game_state);
+    }
+
+    // Tell the discovery topic which lobby we're in, with the rules we play by
+    fn advertise_lobby(&self, swarm: &mut libp2p::swarm::Swarm<GameBehaviour>, game_state: &Arc<Mutex<GameState>>) {
+        let node_id = self.peer_id.to_string();
+        let advert = {
+            let mut state = game_state.lock().unwrap();
+            let (rules, map_hash) = (state.params, state.map.hash());
+            state.record_lobby(self.lobby.as_deref(), &node_id, rules, map_hash);
+            P2PMessage::LobbyAdvert { node_id, lobby: self.lobby.clone(), rules, map_hash }
+        };
+        self.publish_to(swarm, self.discovery_topic.clone(), &advert);
+    }
+
+    // Publish a message to our lobby's topic, returning whether it went out
+    fn publish(&self, swarm: &mut libp2p::swarm::Swarm<GameBehaviour>, msg: &P2PMessage) -> bool {
+        match self.lobby_topic() {
+            Some(topic) => self.publish_to(swarm, topic, msg),
+            None => false,
+        }
+    }
+
+    // Publish a message to a topic, returning whether it went out.
+    // Having no peers yet isn't an error worth logging.
+    fn publish_to(&self, swarm: &mut libp2p::swarm::Swarm<GameBehaviour>, topic: IdentTopic, msg: &P2PMessage) -> bool {
+        let data = match codec::encode(msg) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Error serializing message: {:?}", e);
+                return false;
+            }
+        };
+        match swarm.behaviour_mut().gossipsub.publish(topic, data) {
+            Ok(_) => true,
+            Err(gossipsub::PublishError::InsufficientPeers) => false,
+            Err(e) => {
+                eprintln!("Error publishing message: {:?}", e);
+                false
+            }
+        }
+    }
+
     // Helper method to broadcast node info
     fn broadcast_node_info(&self, swarm: &mut libp2p::swarm::Swarm<GameBehaviour>) -> bool {
+        let Some(topic) = self.lobby_topic() else {
+            return false;
+        };
         let node_info = P2PMessage::NodeInfo {
             node_id: self.peer_id.to_string(),
             name: self.node_name.clone(),
@@ -360,9 +1308,9 @@ impl P2PNode {
         };
         
         // Serialize and publish the node info message
-        match serde_json::to_vec(&node_info) {
+        match codec::encode(&node_info) {
             Ok(data) => {
-                match swarm.behaviour_mut().gossipsub.publish(self.topic.clone(), data) {
+                match swarm.behaviour_mut().gossipsub.publish(topic, data) {
                     Ok(_) => {
                         println!("Successfully sent node info to peers");
                         true
@@ -386,29 +1334,1129 @@ impl P2PNode {
     }
 }
 
+// Why a verified proof doesn't fit our view of its player's chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ChainError {
+    // At or before the last batch we accepted from this player
+    Replay,
+    // From an earlier run of the player's node than the last batch we accepted
+    StaleEpoch,
+    // Batches `from..=to` of `epoch` haven't reached us yet
+    Gap { epoch: u64, from: u64, to: u64 },
+    // Proven by someone other than the player it came from
+    WrongPlayer,
+    // Played in another lobby; it still continues the player's chain
+    DifferentMatch,
+    DifferentMap,
+    DifferentParams,
+    UnexpectedSeed,
+    // Doesn't start where the last accepted batch ended
+    BrokenChain,
+    // Seeded from a batch that hasn't reached us yet
+    UnknownBeacon(BatchId),
+    // Seeded from the player's own batch
+    OwnBeacon,
+    // Seeded from an old batch while newer ones were out
+    StaleBeacon,
+    // Seeded from another batch than the one announced when it was cut
+    CutMismatch,
+    // Not seeded from a beacon, though the player's earlier batches this epoch were
+    MissingBeacon,
+}
+
+impl std::fmt::Display for ChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChainError::Replay => write!(f, "batch was already accepted"),
+            ChainError::StaleEpoch => write!(f, "stale epoch"),
+            ChainError::Gap { epoch, from, to } => write!(f, "batches {}-{} of epoch {} are missing", from, to, epoch),
+            ChainError::WrongPlayer => write!(f, "proven by another player"),
+            ChainError::DifferentMatch => write!(f, "played in another lobby"),
+            ChainError::DifferentMap => write!(f, "different map"),
+            ChainError::DifferentParams => write!(f, "different game parameters"),
+            ChainError::UnexpectedSeed => write!(f, "unexpected reveal seed"),
+            ChainError::BrokenChain => write!(f, "broken position chain"),
+            ChainError::UnknownBeacon(batch) => {
+                write!(f, "reveal seeded from unknown batch {} of epoch {} from {}", batch.sequence, batch.epoch, batch.player_id)
+            }
+            ChainError::OwnBeacon => write!(f, "reveal seeded from the player's own batch"),
+            ChainError::StaleBeacon => write!(f, "reveal seeded from a stale batch"),
+            ChainError::CutMismatch => write!(f, "reveal seeded from another batch than announced"),
+            ChainError::MissingBeacon => write!(f, "reveal not seeded from another player's batch"),
+        }
+    }
+}
+
+// Check that a verified proof belongs to our game and continues its player's
+// chain: the next batch of the same epoch, or the first batch of a later one.
+// A later epoch is a restart of the player's node, which must carry on from the
+// same position and journal, so it's held to the same chain checks.
+fn check_chain(state: &GameState, player_id: &str, outputs: &Outputs) -> Result<(), ChainError> {
+    // Only the player who proved a batch can publish it or serve it as theirs
+    if outputs.player_id != player_id {
+        return Err(ChainError::WrongPlayer);
+    }
+    let player = state.remote_players.get(player_id);
+    let last = player.and_then(|player| player.epoch.zip(player.last_sequence));
+    check_sequence(player, outputs.epoch, outputs.sequence)?;
+
+    // The reveal window must be seeded from this player's previous journal and
+    // the beacon, and the batch must start where their last accepted one ended
+    let previous_journal = player.and_then(|player| player.journal_digest);
+    let chain_error = if previous_journal
+        .is_some_and(|previous| outputs.reveal_seed != reveal_seed(&previous, outputs.reveal_beacon.as_ref()))
+    {
+        Some(ChainError::UnexpectedSeed)
+    } else if player
+        .and_then(|player| player.end_commitment)
+        .is_some_and(|previous_end| previous_end != outputs.start_commitment)
+    {
+        Some(ChainError::BrokenChain)
+    } else {
+        None
+    };
+    match (chain_error, last) {
+        (None, _) => check_match(state, player_id, outputs),
+        // The first batch of a new epoch that doesn't fit may follow batches
+        // from the end of the previous epoch that we missed
+        (Some(_), Some((epoch, last_sequence))) if outputs.epoch > epoch => Err(ChainError::Gap {
+            epoch,
+            from: last_sequence + 1,
+            to: last_sequence + 1,
+        }),
+        (Some(e), _) => Err(e),
+    }
+}
+
+// Check that a batch that continues its player's chain was played in our
+// match, under our rules
+fn check_match(state: &GameState, player_id: &str, outputs: &Outputs) -> Result<(), ChainError> {
+    if outputs.match_id != lobby::match_id(state.lobby.as_deref()) {
+        return Err(ChainError::DifferentMatch);
+    }
+    if outputs.map_hash != state.map.hash() {
+        return Err(ChainError::DifferentMap);
+    }
+    if outputs.params != state.params {
+        return Err(ChainError::DifferentParams);
+    }
+    check_beacon(state, player_id, outputs)
+}
+
+// Check that a batch is the one that follows the last we accepted from its player
+fn check_sequence(player: Option<&RemotePlayer>, epoch: u64, sequence: u64) -> Result<(), ChainError> {
+    let Some((last_epoch, last_sequence)) = player.and_then(|player| player.epoch.zip(player.last_sequence)) else {
+        return Ok(());
+    };
+    if epoch < last_epoch {
+        return Err(ChainError::StaleEpoch);
+    }
+    let next = if epoch == last_epoch { last_sequence + 1 } else { 0 };
+    if sequence < next {
+        return Err(ChainError::Replay);
+    }
+    if sequence > next {
+        return Err(ChainError::Gap { epoch, from: next, to: sequence - 1 });
+    }
+    Ok(())
+}
+
+// Whether a beacon is one of the newest batches we know from its player.
+// Beacons from players we've heard nothing from can't be judged yet.
+fn beacon_is_fresh(state: &GameState, beacon: &RevealBeacon) -> bool {
+    state
+        .latest_batch(&beacon.player_id)
+        .is_none_or(|(epoch, sequence)| match beacon.epoch.cmp(&epoch) {
+            Ordering::Equal => beacon.sequence + BEACON_LAG >= sequence,
+            Ordering::Less => sequence < BEACON_LAG,
+            Ordering::Greater => true,
+        })
+}
+
+// Record the beacon a player announced for a batch it started proving, judged
+// against what we know now, since proving can take long enough for newer
+// batches to come along. A batch cut again after its proof failed replaces
+// the earlier announcement.
+fn record_cut(state: &mut GameState, player_id: &str, epoch: u64, sequence: u64, beacon: Option<RevealBeacon>) {
+    let fresh = beacon.as_ref().is_none_or(|beacon| beacon_is_fresh(state, beacon));
+    let player = state.remote_player(player_id);
+    if player.epoch.zip(player.last_sequence).is_some_and(|last| (epoch, sequence) <= last) {
+        return;
+    }
+    player.cuts.insert((epoch, sequence), (beacon, fresh));
+    while player.cuts.len() > MAX_CUTS {
+        player.cuts.pop_first();
+    }
+}
+
+// Check the other player's batch a reveal was seeded from. The prover can't
+// know its journal in advance, so it can't line up a seed for the moves it's
+// about to make; it has to be recent, so it can't pick among old ones either.
+// Its freshness is judged when the batch's cut was announced, or on arrival
+// if we missed that. A player seeds from beacons once it has verified
+// someone else's batch, and keeps doing so for the rest of its epoch.
+fn check_beacon(state: &GameState, player_id: &str, outputs: &Outputs) -> Result<(), ChainError> {
+    let Some(beacon) = &outputs.reveal_beacon else {
+        let seeding_since = state.remote_players.get(player_id).and_then(|player| player.beacon_epoch);
+        return match seeding_since {
+            Some(epoch) if epoch == outputs.epoch => Err(ChainError::MissingBeacon),
+            _ => Ok(()),
+        };
+    };
+    if beacon.player_id == player_id {
+        return Err(ChainError::OwnBeacon);
+    }
+
+    let cut = state
+        .remote_players
+        .get(player_id)
+        .and_then(|player| player.cuts.get(&(outputs.epoch, outputs.sequence)));
+    let fresh = match cut {
+        Some((announced, _)) if announced.as_ref() != Some(beacon) => return Err(ChainError::CutMismatch),
+        Some((_, fresh)) => *fresh,
+        None => beacon_is_fresh(state, beacon),
+    };
+    if !fresh {
+        return Err(ChainError::StaleBeacon);
+    }
+    if state.knows_batch(beacon) {
+        Ok(())
+    } else if state.knows_batch_number(&beacon.player_id, beacon.epoch, beacon.sequence) {
+        Err(ChainError::UnexpectedSeed)
+    } else {
+        Err(ChainError::UnknownBeacon(BatchId {
+            player_id: beacon.player_id.clone(),
+            epoch: beacon.epoch,
+            sequence: beacon.sequence,
+        }))
+    }
+}
+
+// Apply a verified proof's outputs to the game state, once it passes every
+// chain check; nothing about the player's chain changes before that.
+// Proofs that arrive ahead of missing batches, or seeded from a batch we
+// haven't seen, are kept until those turn up, then applied in order.
+fn apply_verified_proof(
+    game_state: &Arc<Mutex<GameState>>,
+    player_id: &str,
+    outputs: Outputs,
+    journal_digest: [u8; 32],
+) -> Result<(), ChainError> {
+    let mut state = game_state.lock().unwrap();
+
+    // Fitting its player's chain or not, the batch was proven, so others may
+    // have seeded their reveals from it
+    let batch = RevealBeacon {
+        player_id: outputs.player_id.clone(),
+        epoch: outputs.epoch,
+        sequence: outputs.sequence,
+        journal_digest,
+    };
+    let newly_known = !state.knows_batch(&batch);
+    state.remember_batch(batch);
+
+    let result = check_chain(&state, player_id, &outputs);
+    match &result {
+        Ok(()) => record_batch(&mut state, player_id, outputs, journal_digest),
+        Err(e) => {
+            println!("Ignoring proof {} of epoch {} from {}: {}", outputs.sequence, outputs.epoch, player_id, e);
+            match e {
+                ChainError::Replay => {}
+                ChainError::Gap { from, to, .. } => {
+                    state.set_proof_status(format!("Proof out of order from {}", player_id));
+                    let player = state.remote_player(player_id);
+                    player.missed_batches = player.missed_batches.max(to - from + 1);
+                    player.status = format!("Missing batches {}-{}", from, to);
+                    hold_early_batch(player, outputs, journal_digest);
+                }
+                ChainError::UnknownBeacon(_) => {
+                    let player = state.remote_player(player_id);
+                    player.status = "Waiting for the batch its reveal was seeded from".to_string();
+                    hold_early_batch(player, outputs, journal_digest);
+                }
+                ChainError::DifferentMatch => link_batch(state.remote_player(player_id), player_id, &outputs, journal_digest),
+                e => {
+                    state.set_proof_status(format!("Proof rejected: {}", e));
+                    state.remote_player(player_id).status = format!("Proof rejected: {}", e);
+                }
+            }
+        }
+    }
+
+    // Batches that were waiting on this one, in their own chain or as a beacon, can follow now
+    let linked = matches!(result, Ok(()) | Err(ChainError::DifferentMatch));
+    if linked || newly_known {
+        let waiting: Vec<String> = state
+            .remote_players
+            .iter()
+            .filter(|(_, player)| !player.early_batches.is_empty())
+            .map(|(player_id, _)| player_id.clone())
+            .collect();
+        for player_id in waiting {
+            apply_early_batches(&mut state, &player_id);
+        }
+    }
+    result
+}
+
+// Keep a batch until what it waits on turns up
+fn hold_early_batch(player: &mut RemotePlayer, outputs: Outputs, journal_digest: [u8; 32]) {
+    if player.early_batches.len() < MAX_EARLY_BATCHES {
+        player.early_batches.insert((outputs.epoch, outputs.sequence), (outputs, journal_digest));
+    }
+}
+
+// Apply a player's held batches in order, up to the first that still has to wait
+fn apply_early_batches(state: &mut GameState, player_id: &str) {
+    while let Some(((epoch, sequence), (outputs, journal_digest))) =
+        state.remote_players.get_mut(player_id).and_then(|player| player.early_batches.pop_first())
+    {
+        match check_chain(state, player_id, &outputs) {
+            Ok(()) => record_batch(state, player_id, outputs, journal_digest),
+            Err(ChainError::DifferentMatch) => link_batch(state.remote_player(player_id), player_id, &outputs, journal_digest),
+            Err(ChainError::Gap { .. } | ChainError::UnknownBeacon(_)) => {
+                state.remote_player(player_id).early_batches.insert((epoch, sequence), (outputs, journal_digest));
+                break;
+            }
+            Err(e) => println!("Dropping early proof {} of epoch {} from {}: {}", sequence, epoch, player_id, e),
+        }
+    }
+}
+
+// Move a player's chain on to a batch that continues it. Batches played in
+// another lobby only do this; their trails aren't shown here.
+fn link_batch(player: &mut RemotePlayer, player_id: &str, outputs: &Outputs, journal_digest: [u8; 32]) {
+    if player.epoch.is_some_and(|epoch| epoch < outputs.epoch) {
+        println!("{} restarted and continues its chain in epoch {}", player_id, outputs.epoch);
+    }
+    player.end_commitment = Some(outputs.end_commitment);
+    player.journal_digest = Some(journal_digest);
+    if outputs.reveal_beacon.is_some() {
+        player.beacon_epoch = Some(outputs.epoch);
+    }
+    player.epoch = Some(outputs.epoch);
+    player.last_sequence = Some(outputs.sequence);
+    player.missed_batches = 0;
+    player.cuts.retain(|&cut, _| cut > (outputs.epoch, outputs.sequence));
+}
+
+// Record a batch that continues a player's chain
+fn record_batch(state: &mut GameState, player_id: &str, outputs: Outputs, journal_digest: [u8; 32]) {
+    // Get the trail length before moving it
+    let trail_len = outputs.trail_positions.len();
+    let trail_summary = format!("{:?}", outputs.trail_positions);
+
+    // Record the verified trail for this player only
+    let player = state.remote_player(player_id);
+    link_batch(player, player_id, &outputs, journal_digest);
+    player.trail = outputs.trail_positions;
+    player.last_proof_time = unix_now();
+    player.batch_count += 1;
+    player.status = format!("Proof verified! Trail: {} positions", trail_len);
+    // Our next batch seeds its reveal from the newest batch we accepted
+    state.reveal_beacon = Some(RevealBeacon {
+        player_id: player_id.to_string(),
+        epoch: outputs.epoch,
+        sequence: outputs.sequence,
+        journal_digest,
+    });
+    state.set_proof_status(format!("Proof verified for {}! Trail: {} positions", player_id, trail_len));
+
+    println!("Batch processed for {}! Trail verified with {} positions: {}",
+        player_id, trail_len, trail_summary);
+}
+
+// Record that a player's proof came from a guest build we don't accept
+fn mark_incompatible(game_state: &Arc<Mutex<GameState>>, player_id: &str) {
+    let mut state = game_state.lock().unwrap();
+    state.set_proof_status("Proof rejected: incompatible client version");
+    state.remote_player(player_id).status = "Incompatible client version".to_string();
+}
+
+// Hand a received receipt to the worker pool so the swarm keeps running while it's verified
+fn queue_verification(
+    swarm: &mut libp2p::swarm::Swarm<GameBehaviour>,
+    game_state: &Arc<Mutex<GameState>>,
+    verifier: &mut VerifierPool,
+    job: VerifyJob,
+) {
+    {
+        let mut state = game_state.lock().unwrap();
+        state.set_proof_status("Verifying proof...");
+        state.remote_player(&job.player_id).status = "Queued for verification".to_string();
+    }
+
+    // A proof we can't take is ignored, not dropped from the chain: the
+    // player's next proof shows the gap, and this one is fetched to fill it
+    if let Err(job) = verifier.submit(job) {
+        println!("Verifier busy, ignoring a proof from {}", job.player_id);
+        for (id, source) in &job.messages {
+            report_validation(swarm, id, source, MessageAcceptance::Ignore);
+        }
+        game_state.lock().unwrap().remote_player(&job.player_id).status = "Proof ignored: verifier busy".to_string();
+    }
+    println!("{} proof(s) waiting for verification", verifier.queued());
+}
+
+// Cache one of our own receipts and build the announcement that can be gossiped in its place
+fn announce_proof(
+    cache: &mut ReceiptCache,
+    player_id: &str,
+    receipt: &Receipt,
+    image_id: [u32; 8],
+) -> Result<P2PMessage, String> {
+    let outputs: Outputs = receipt
+        .journal
+        .decode()
+        .map_err(|e| format!("invalid journal: {:?}", e))?;
+    let receipt_hash = receipts::receipt_hash(receipt).map_err(|e| e.to_string())?;
+    let batch = BatchId { player_id: player_id.to_string(), epoch: outputs.epoch, sequence: outputs.sequence };
+    cache.insert(receipt_hash, batch, receipt.clone());
+    Ok(P2PMessage::ProofAnnouncement {
+        player_id: player_id.to_string(),
+        epoch: outputs.epoch,
+        sequence: outputs.sequence,
+        journal_digest: journal_digest(&receipt.journal.bytes),
+        receipt_hash,
+        ImageID: image_id,
+    })
+}
+
+// Batches missing from a player's chain
+struct MissingBatches<'a> {
+    player_id: &'a str,
+    image_id: [u32; 8], // Guest image the player's later batch was proven with
+    epoch: u64,
+    sequences: RangeInclusive<u64>,
+}
+
+// Ask for the batches missing from a player's chain, first from the given peer
+// (the one that relayed the batch after them, or that seeded a reveal from
+// one) and then from the player. At most
+// MAX_GAP_FETCH are asked for; a later batch reveals the rest of a longer gap.
+fn fetch_missing(
+    swarm: &mut libp2p::swarm::Swarm<GameBehaviour>,
+    pending_fetches: &mut HashMap<request_response::RequestId, PendingFetch>,
+    missing: MissingBatches<'_>,
+    relay: Option<PeerId>,
+) {
+    let prover = missing.player_id.parse::<PeerId>().ok();
+    let Some(first) = relay.or(prover) else {
+        return;
+    };
+    let fallback = prover.filter(|prover| *prover != first);
+
+    for sequence in missing.sequences.take(MAX_GAP_FETCH as usize) {
+        let request = ReceiptRequest::Batch(BatchId {
+            player_id: missing.player_id.to_string(),
+            epoch: missing.epoch,
+            sequence,
+        });
+        if pending_fetches.values().any(|fetch| fetch.request == request) {
+            continue;
+        }
+        println!("Fetching {} from {}", request, first);
+        let fetch = PendingFetch {
+            player_id: missing.player_id.to_string(),
+            image_id: missing.image_id,
+            request,
+            journal_digest: None,
+            messages: Vec::new(),
+            fallback,
+        };
+        request_receipt(swarm, pending_fetches, first, fetch);
+    }
+}
+
+// Ask a peer for a receipt
+fn request_receipt(
+    swarm: &mut libp2p::swarm::Swarm<GameBehaviour>,
+    pending_fetches: &mut HashMap<request_response::RequestId, PendingFetch>,
+    peer: PeerId,
+    fetch: PendingFetch,
+) {
+    let request_id = swarm.behaviour_mut().receipts.send_request(&peer, fetch.request.clone());
+    pending_fetches.insert(request_id, fetch);
+}
+
+// Whether a receipt is the one a request asked for. A batch is matched on the
+// player its proof commits, so another player's batch with the same numbers isn't taken.
+fn serves(request: &ReceiptRequest, receipt: &Receipt) -> bool {
+    match request {
+        ReceiptRequest::Hash(hash) => receipts::receipt_hash(receipt).ok() == Some(*hash),
+        ReceiptRequest::Batch(batch) => receipt.journal.decode::<Outputs>().is_ok_and(|outputs| {
+            outputs.player_id == batch.player_id && outputs.epoch == batch.epoch && outputs.sequence == batch.sequence
+        }),
+    }
+}
+
+// Try the next peer for a receipt, or give up on the announcement if there is none
+fn retry_fetch(
+    swarm: &mut libp2p::swarm::Swarm<GameBehaviour>,
+    game_state: &Arc<Mutex<GameState>>,
+    pending_fetches: &mut HashMap<request_response::RequestId, PendingFetch>,
+    mut fetch: PendingFetch,
+) {
+    if let Some(peer) = fetch.fallback.take() {
+        request_receipt(swarm, pending_fetches, peer, fetch);
+        return;
+    }
+
+    println!("Giving up on {}", fetch.request);
+    game_state.lock().unwrap().remote_player(&fetch.player_id).status = "Receipt unavailable".to_string();
+    for (id, source) in &fetch.messages {
+        report_validation(swarm, id, source, MessageAcceptance::Ignore);
+    }
+}
+
+// Tell gossipsub whether a message we held back should be propagated
+fn report_validation(
+    swarm: &mut libp2p::swarm::Swarm<GameBehaviour>,
+    message_id: &MessageId,
+    propagation_source: &PeerId,
+    acceptance: MessageAcceptance,
+) {
+    if let Err(e) = swarm
+        .behaviour_mut()
+        .gossipsub
+        .report_message_validation_result(message_id, propagation_source, acceptance)
+    {
+        eprintln!("Error reporting validation result for {}: {:?}", message_id, e);
+    }
+}
+
+// Peer scoring for our topics. Every rejected message counts as an invalid
+// delivery; a handful of bad proofs or undecodable messages drops a peer below
+// the graylist threshold, after which its messages are ignored.
+fn topic_score_params() -> TopicScoreParams {
+    TopicScoreParams {
+        topic_weight: 1.0,
+        invalid_message_deliveries_weight: -10.0,
+        invalid_message_deliveries_decay: 0.9,
+        // Players publish rarely, so don't penalise quiet mesh peers
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        ..Default::default()
+    }
+}
+
+// Peer-level scoring; topics are added with `topic_score_params` as we join them
+fn peer_score_config() -> (PeerScoreParams, PeerScoreThresholds) {
+    let params = PeerScoreParams {
+        // Several nodes on one machine or LAN is the normal way to play
+        ip_colocation_factor_weight: 0.0,
+        ..Default::default()
+    };
+
+    let thresholds = PeerScoreThresholds {
+        gossip_threshold: -10.0,
+        publish_threshold: -50.0,
+        graylist_threshold: -80.0,
+        ..Default::default()
+    };
+
+    (params, thresholds)
+}
+
 // Helper function to start a P2P node
 pub async fn start_p2p_node(
-    node_name: String,
+    config: P2PConfig,
     game_state: Arc<Mutex<GameState>>,
-    p2p_port: u16,
-    known_peers: Vec<(String, u16)>,
-    custom_url: String,
-) -> Result<(mpsc::Sender<P2PMessage>, mpsc::Receiver<String>), Box<dyn Error>> {
+) -> Result<(P2PHandle, mpsc::Receiver<ServerMessage>), Box<dyn Error>> {
     // Create a channel for connection events
-    let (connection_tx, connection_rx) = mpsc::channel::<String>(100);
+    let (connection_tx, connection_rx) = mpsc::channel::<ServerMessage>(100);
+
+    // Load the persistent identity shared by the transport, gossipsub and PeerId
+    let keypair = load_or_create_keypair(&config.key_file)?;
     
     // Create a new P2P node
-    let node = P2PNode::new("footsteps-game", known_peers, connection_tx, node_name, custom_url)?;
+    let node = P2PNode::new(keypair, connection_tx, config)?;
 
-    // Get a sender for sending messages to the P2P network
-    let sender = node.sender();
+    // Get a handle for sending messages to the P2P network
+    let handle = node.handle();
 
     // Start the node in a separate task
     tokio::spawn(async move {
-        if let Err(e) = node.start(game_state, p2p_port).await {
+        if let Err(e) = node.start(game_state).await {
             eprintln!("Error starting P2P node: {:?}", e);
         }
     });
 
-    Ok((sender, connection_rx))
+    Ok((handle, connection_rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::fake_receipt;
+    use footsteps_core::{position_commitment, GameMap, GridPos, SALT_LEN};
+
+    fn gossip(source: Option<PeerId>, msg: &P2PMessage) -> gossipsub::Message {
+        gossipsub::Message {
+            source,
+            data: codec::encode(msg).unwrap(),
+            sequence_number: Some(1),
+            topic: IdentTopic::new("footsteps-game").hash(),
+        }
+    }
+
+    fn proof_from(player_id: String) -> P2PMessage {
+        P2PMessage::Proof {
+            player_id,
+            receipt: fake_receipt(),
+            ImageID: FOOTSTEPS_GUEST_ID,
+        }
+    }
+
+    // Builds a player's proofs the way their pipeline would
+    #[derive(Clone)]
+    struct TestChain {
+        salt: [u8; SALT_LEN],
+        epoch: u64,
+        next_sequence: u64,
+        end: GridPos,
+        journal_digest: [u8; 32],
+        beacon: Option<RevealBeacon>,
+        player_id: String,
+        match_id: [u8; 32],
+    }
+
+    impl TestChain {
+        fn new() -> Self {
+            Self {
+                salt: [1; SALT_LEN],
+                epoch: 0,
+                next_sequence: 0,
+                end: GridPos::default(),
+                journal_digest: [0; 32],
+                beacon: None,
+                player_id: "alice".to_string(),
+                match_id: lobby::match_id(None),
+            }
+        }
+
+        // Outputs of the next batch, moving to `to`, with its journal digest
+        fn batch(&mut self, to: GridPos) -> (Outputs, [u8; 32]) {
+            let outputs = Outputs {
+                trail_positions: vec![self.end],
+                start_commitment: position_commitment(self.end, &self.salt),
+                end_commitment: position_commitment(to, &self.salt),
+                map_hash: GameMap::default().hash(),
+                params: GameParams::default(),
+                reveal_seed: reveal_seed(&self.journal_digest, self.beacon.as_ref()),
+                reveal_beacon: self.beacon.clone(),
+                reveal_indices: vec![0],
+                epoch: self.epoch,
+                sequence: self.next_sequence,
+                player_id: self.player_id.clone(),
+                match_id: self.match_id,
+            };
+            let digest = journal_digest(format!("{:?}", outputs).as_bytes());
+            self.next_sequence += 1;
+            self.end = to;
+            self.journal_digest = digest;
+            (outputs, digest)
+        }
+
+        // A restarted node carries on from the saved chain
+        fn restart(&mut self) {
+            self.epoch += 1;
+            self.next_sequence = 0;
+        }
+    }
+
+    fn apply(game_state: &Arc<Mutex<GameState>>, (outputs, digest): (Outputs, [u8; 32])) -> Result<(), ChainError> {
+        apply_verified_proof(game_state, "alice", outputs, digest)
+    }
+
+    fn last_batch(game_state: &Arc<Mutex<GameState>>) -> (Option<u64>, Option<u64>, usize) {
+        let state = game_state.lock().unwrap();
+        let player = &state.remote_players["alice"];
+        (player.epoch, player.last_sequence, player.batch_count)
+    }
+
+    #[test]
+    fn follows_a_chain_across_restarts() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(1, 0))), Ok(()));
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(2, 0))), Ok(()));
+        chain.restart();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(3, 0))), Ok(()));
+        assert_eq!(last_batch(&game_state), (Some(1), Some(0), 3));
+    }
+
+    #[test]
+    fn ignores_replays_and_stale_epochs() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        let first = chain.batch(GridPos::new(1, 0));
+        assert_eq!(apply(&game_state, first.clone()), Ok(()));
+        let second = chain.batch(GridPos::new(2, 0));
+        assert_eq!(apply(&game_state, second.clone()), Ok(()));
+        assert_eq!(apply(&game_state, first), Err(ChainError::Replay));
+        assert_eq!(apply(&game_state, second.clone()), Err(ChainError::Replay));
+
+        // After a restart, nothing from the old epoch is taken, not even its first batch
+        chain.restart();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(3, 0))), Ok(()));
+        assert_eq!(apply(&game_state, second), Err(ChainError::StaleEpoch));
+        assert_eq!(last_batch(&game_state), (Some(1), Some(0), 3));
+    }
+
+    #[test]
+    fn ignores_batches_from_other_lobbies() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(1, 0))), Ok(()));
+
+        // Its trail isn't shown here, but the chain still runs through it
+        chain.match_id = lobby::match_id(Some("elsewhere"));
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(2, 0))), Err(ChainError::DifferentMatch));
+        assert_eq!(game_state.lock().unwrap().remote_players["alice"].trail, vec![GridPos::default()]);
+        chain.match_id = lobby::match_id(None);
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(3, 0))), Ok(()));
+        assert_eq!(last_batch(&game_state), (Some(0), Some(2), 2));
+    }
+
+    #[test]
+    fn ignores_batches_passed_off_as_another_players() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        let first = chain.batch(GridPos::new(1, 0));
+        assert_eq!(apply_verified_proof(&game_state, "mallory", first.0.clone(), first.1), Err(ChainError::WrongPlayer));
+        let state = game_state.lock().unwrap();
+        assert_eq!((state.remote_players["mallory"].epoch, state.remote_players["mallory"].batch_count), (None, 0));
+        drop(state);
+        assert_eq!(apply(&game_state, first), Ok(()));
+    }
+
+    #[test]
+    fn places_announced_batches_against_the_chain() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(1, 0))), Ok(()));
+        chain.restart();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(2, 0))), Ok(()));
+
+        let state = game_state.lock().unwrap();
+        let alice = state.remote_players.get("alice");
+        assert_eq!(check_sequence(alice, 0, 1), Err(ChainError::StaleEpoch));
+        assert_eq!(check_sequence(alice, 1, 0), Err(ChainError::Replay));
+        assert_eq!(check_sequence(alice, 1, 1), Ok(()));
+        assert_eq!(check_sequence(alice, 2, 1), Err(ChainError::Gap { epoch: 2, from: 0, to: 0 }));
+        assert_eq!(check_sequence(None, 5, 3), Ok(()));
+    }
+
+    #[test]
+    fn applies_early_batches_once_the_gap_is_filled() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(1, 0))), Ok(()));
+        let first_missing = chain.batch(GridPos::new(2, 0));
+        let second_missing = chain.batch(GridPos::new(3, 0));
+        let early = chain.batch(GridPos::new(4, 0));
+        assert_eq!(apply(&game_state, early), Err(ChainError::Gap { epoch: 0, from: 1, to: 2 }));
+        assert_eq!(last_batch(&game_state), (Some(0), Some(0), 1));
+        assert_eq!(game_state.lock().unwrap().remote_players["alice"].missed_batches, 2);
+
+        // Fetched batches can come back in any order
+        assert_eq!(apply(&game_state, second_missing), Err(ChainError::Gap { epoch: 0, from: 1, to: 1 }));
+        assert_eq!(apply(&game_state, first_missing), Ok(()));
+        assert_eq!(last_batch(&game_state), (Some(0), Some(3), 4));
+        assert!(game_state.lock().unwrap().remote_players["alice"].early_batches.is_empty());
+    }
+
+    #[test]
+    fn looks_for_the_end_of_the_previous_epoch() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(1, 0))), Ok(()));
+        let missing = chain.batch(GridPos::new(2, 0));
+        chain.restart();
+        let skipped = chain.batch(GridPos::new(3, 0));
+        let restarted = chain.batch(GridPos::new(4, 0));
+
+        // A new epoch that doesn't start at its first batch is missing batches
+        assert_eq!(apply(&game_state, restarted), Err(ChainError::Gap { epoch: 1, from: 0, to: 0 }));
+        // And a first batch that doesn't fit may follow batches we missed from the old epoch
+        assert_eq!(apply(&game_state, skipped), Err(ChainError::Gap { epoch: 0, from: 1, to: 1 }));
+        assert_eq!(apply(&game_state, missing), Ok(()));
+        assert_eq!(last_batch(&game_state), (Some(1), Some(1), 4));
+    }
+
+    #[test]
+    fn rejects_a_broken_chain_without_resetting_it() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut chain = TestChain::new();
+        assert_eq!(apply(&game_state, chain.batch(GridPos::new(1, 0))), Ok(()));
+        let anchor = game_state.lock().unwrap().remote_players["alice"].end_commitment;
+
+        // Teleporting within an epoch
+        let mut teleport = TestChain { end: GridPos::new(5, 5), ..TestChain::new() };
+        teleport.next_sequence = 1;
+        teleport.journal_digest = chain.journal_digest;
+        assert_eq!(apply(&game_state, teleport.batch(GridPos::new(5, 6))), Err(ChainError::BrokenChain));
+        let mut reseeded = TestChain::new();
+        reseeded.next_sequence = 1;
+        reseeded.end = chain.end;
+        assert_eq!(apply(&game_state, reseeded.batch(GridPos::new(2, 0))), Err(ChainError::UnexpectedSeed));
+        assert!(game_state.lock().unwrap().remote_players["alice"].status.contains("Proof rejected"));
+
+        // Or by claiming a restart: it's only ever taken for a missing batch,
+        // and never applied unless the real one turns up
+        let mut restart = TestChain { epoch: 1, end: GridPos::new(5, 5), ..TestChain::new() };
+        restart.journal_digest = chain.journal_digest;
+        assert_eq!(apply(&game_state, restart.batch(GridPos::new(5, 6))), Err(ChainError::Gap { epoch: 0, from: 1, to: 1 }));
+
+        let state = game_state.lock().unwrap();
+        let player = &state.remote_players["alice"];
+        assert_eq!(player.end_commitment, anchor);
+        assert_eq!((player.epoch, player.last_sequence, player.batch_count), (Some(0), Some(0), 1));
+    }
+
+    // A beacon naming one of a player's batches
+    fn beacon(player_id: &str, (outputs, digest): &(Outputs, [u8; 32])) -> RevealBeacon {
+        RevealBeacon {
+            player_id: player_id.to_string(),
+            epoch: outputs.epoch,
+            sequence: outputs.sequence,
+            journal_digest: *digest,
+        }
+    }
+
+    #[test]
+    fn holds_batches_until_their_beacon_turns_up() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut alice = TestChain::new();
+        let mut bob = TestChain { salt: [2; SALT_LEN], player_id: "bob".to_string(), ..TestChain::new() };
+        assert_eq!(apply(&game_state, alice.batch(GridPos::new(1, 0))), Ok(()));
+
+        let bobs = bob.batch(GridPos::new(0, 1));
+        alice.beacon = Some(beacon("bob", &bobs));
+        let seeded = alice.batch(GridPos::new(2, 0));
+        let next = alice.batch(GridPos::new(3, 0));
+        let unknown = BatchId { player_id: "bob".to_string(), epoch: 0, sequence: 0 };
+        assert_eq!(apply(&game_state, seeded), Err(ChainError::UnknownBeacon(unknown)));
+        assert_eq!(apply(&game_state, next), Err(ChainError::Gap { epoch: 0, from: 1, to: 1 }));
+        assert_eq!(last_batch(&game_state), (Some(0), Some(0), 1));
+
+        // Verifying bob's batch lets alice's follow
+        assert_eq!(apply_verified_proof(&game_state, "bob", bobs.0, bobs.1), Ok(()));
+        assert_eq!(last_batch(&game_state), (Some(0), Some(2), 3));
+    }
+
+    #[test]
+    fn rejects_unusable_beacons() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut alice = TestChain::new();
+        let mut bob = TestChain { salt: [2; SALT_LEN], player_id: "bob".to_string(), ..TestChain::new() };
+        let oldest = bob.batch(GridPos::new(0, 1));
+        assert_eq!(apply_verified_proof(&game_state, "bob", oldest.0.clone(), oldest.1), Ok(()));
+        let mut newest = oldest.clone();
+        for y in 0..=BEACON_LAG {
+            newest = bob.batch(GridPos::new(0, y as i32 + 2));
+            assert_eq!(apply_verified_proof(&game_state, "bob", newest.0.clone(), newest.1), Ok(()));
+        }
+        // Our own next batch seeds from bob's newest
+        assert_eq!(game_state.lock().unwrap().reveal_beacon, Some(beacon("bob", &newest)));
+
+        let first = alice.batch(GridPos::new(1, 0));
+        assert_eq!(apply(&game_state, first.clone()), Ok(()));
+        let rejected = |beacon: RevealBeacon| {
+            let mut chain = alice.clone();
+            chain.beacon = Some(beacon);
+            apply(&game_state, chain.batch(GridPos::new(2, 0)))
+        };
+        assert_eq!(rejected(beacon("bob", &oldest)), Err(ChainError::StaleBeacon));
+        assert_eq!(rejected(beacon("alice", &first)), Err(ChainError::OwnBeacon));
+        let forged = RevealBeacon { journal_digest: [9; 32], ..beacon("bob", &newest) };
+        assert_eq!(rejected(forged), Err(ChainError::UnexpectedSeed));
+
+        alice.beacon = Some(beacon("bob", &newest));
+        assert_eq!(apply(&game_state, alice.batch(GridPos::new(2, 0))), Ok(()));
+        // Once seeding from beacons, a player can't drop them for the rest of the epoch
+        alice.beacon = None;
+        let mut unseeded = alice.clone();
+        assert_eq!(apply(&game_state, unseeded.batch(GridPos::new(3, 0))), Err(ChainError::MissingBeacon));
+        alice.restart();
+        assert_eq!(apply(&game_state, alice.batch(GridPos::new(3, 0))), Ok(()));
+        assert_eq!(last_batch(&game_state), (Some(1), Some(0), 3));
+    }
+
+    #[test]
+    fn judges_beacons_when_their_batch_was_cut() {
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let mut alice = TestChain::new();
+        let mut bob = TestChain { salt: [2; SALT_LEN], player_id: "bob".to_string(), ..TestChain::new() };
+        let bobs: Vec<_> = (0..4).map(|y| bob.batch(GridPos::new(0, y + 1))).collect();
+        let publish = |batch: &(Outputs, [u8; 32])| apply_verified_proof(&game_state, "bob", batch.0.clone(), batch.1);
+        let cut = |sequence, batch: &(Outputs, [u8; 32])| {
+            record_cut(&mut game_state.lock().unwrap(), "alice", 0, sequence, Some(beacon("bob", batch)));
+        };
+        assert_eq!(publish(&bobs[0]), Ok(()));
+        assert_eq!(publish(&bobs[1]), Ok(()));
+        assert_eq!(apply(&game_state, alice.batch(GridPos::new(1, 0))), Ok(()));
+
+        // Bob's newest was fresh when alice cut her batch, however long its proof took
+        cut(1, &bobs[1]);
+        assert_eq!(publish(&bobs[2]), Ok(()));
+        assert_eq!(publish(&bobs[3]), Ok(()));
+        alice.beacon = Some(beacon("bob", &bobs[1]));
+        assert_eq!(apply(&game_state, alice.batch(GridPos::new(2, 0))), Ok(()));
+
+        // Without a cut, only bob's two newest batches will do
+        let seeded = |beacon: RevealBeacon| {
+            let mut chain = alice.clone();
+            chain.beacon = Some(beacon);
+            apply(&game_state, chain.batch(GridPos::new(3, 0)))
+        };
+        assert_eq!(seeded(beacon("bob", &bobs[1])), Err(ChainError::StaleBeacon));
+
+        // With one, the proof must seed from the beacon it announced, and that must have been fresh
+        cut(2, &bobs[3]);
+        assert_eq!(seeded(beacon("bob", &bobs[2])), Err(ChainError::CutMismatch));
+        cut(2, &bobs[1]);
+        assert_eq!(seeded(beacon("bob", &bobs[1])), Err(ChainError::StaleBeacon));
+        cut(2, &bobs[2]);
+        assert_eq!(seeded(beacon("bob", &bobs[2])), Ok(()));
+        assert!(game_state.lock().unwrap().remote_players["alice"].cuts.is_empty());
+    }
+
+    #[test]
+    fn only_takes_the_batch_that_was_asked_for() {
+        let (outputs, _) = TestChain::new().batch(GridPos::new(1, 0));
+        let words = risc0_zkvm::serde::to_vec(&outputs).unwrap();
+        let journal: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        let claim = risc0_zkvm::ReceiptClaim::ok(FOOTSTEPS_GUEST_ID, journal.clone());
+        let receipt = Receipt::new(InnerReceipt::Fake(risc0_zkvm::FakeReceipt::new(claim)), journal);
+
+        let request = |player_id: &str, sequence| {
+            ReceiptRequest::Batch(BatchId { player_id: player_id.to_string(), epoch: 0, sequence })
+        };
+        assert!(serves(&request("alice", 0), &receipt));
+        assert!(!serves(&request("alice", 1), &receipt));
+        assert!(!serves(&request("bob", 0), &receipt));
+    }
+
+    #[test]
+    fn accepts_proof_signed_by_claimed_player() {
+        let local = PeerId::random();
+        let remote = PeerId::random();
+
+        let result = authenticate_message(&local, &gossip(Some(remote), &proof_from(remote.to_string())));
+        assert!(matches!(result, Ok(P2PMessage::Proof { player_id, .. }) if player_id == remote.to_string()));
+    }
+
+    #[test]
+    fn rejects_proof_spoofing_another_player() {
+        let local = PeerId::random();
+        let forger = PeerId::random();
+        let victim = PeerId::random();
+
+        let result = authenticate_message(&local, &gossip(Some(forger), &proof_from(victim.to_string())));
+        assert_eq!(
+            result.unwrap_err(),
+            MessageRejection::IdentityMismatch { claimed: victim.to_string(), source: forger }
+        );
+    }
+
+    #[test]
+    fn rejects_proof_claiming_a_node_name() {
+        // Old-style "<node>-player" ids must not be accepted, nor mistaken for our own messages
+        let local = PeerId::random();
+        let forger = PeerId::random();
+
+        let result = authenticate_message(&local, &gossip(Some(forger), &proof_from("node-player".to_string())));
+        assert!(matches!(result, Err(MessageRejection::IdentityMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_proof_claiming_our_identity() {
+        let local = PeerId::random();
+        let forger = PeerId::random();
+
+        let result = authenticate_message(&local, &gossip(Some(forger), &proof_from(local.to_string())));
+        assert_eq!(
+            result.unwrap_err(),
+            MessageRejection::IdentityMismatch { claimed: local.to_string(), source: forger }
+        );
+    }
+
+    #[test]
+    fn rejects_unsigned_and_own_messages() {
+        let local = PeerId::random();
+        let msg = proof_from(local.to_string());
+
+        assert_eq!(authenticate_message(&local, &gossip(None, &msg)).unwrap_err(), MessageRejection::Unsigned);
+        assert_eq!(authenticate_message(&local, &gossip(Some(local), &msg)).unwrap_err(), MessageRejection::OwnMessage);
+    }
+
+    #[test]
+    fn rejects_forged_node_info_presence_and_announcements() {
+        let local = PeerId::random();
+        let forger = PeerId::random();
+        let victim = PeerId::random().to_string();
+
+        let forged = [
+            P2PMessage::NodeInfo { node_id: victim.clone(), name: "victim".to_string(), custom_url: String::new() },
+            P2PMessage::PlayerJoined { player_id: victim.clone(), name: "victim".to_string() },
+            P2PMessage::PlayerLeft { player_id: victim.clone() },
+            P2PMessage::Heartbeat { player_id: victim.clone() },
+            P2PMessage::LobbyAdvert {
+                node_id: victim.clone(),
+                lobby: Some("default".to_string()),
+                rules: GameParams::default(),
+                map_hash: [0; 32],
+            },
+            P2PMessage::ProofAnnouncement {
+                player_id: victim.clone(),
+                epoch: 0,
+                sequence: 0,
+                journal_digest: [0; 32],
+                receipt_hash: [0; 32],
+                ImageID: FOOTSTEPS_GUEST_ID,
+            },
+            P2PMessage::ProofChunk {
+                player_id: victim.clone(),
+                content_hash: [0; 32],
+                index: 0,
+                total: 1,
+                data: vec![0; 16],
+            },
+            P2PMessage::BatchCut { player_id: victim.clone(), epoch: 0, sequence: 0, beacon: None },
+        ];
+        for msg in &forged {
+            let result = authenticate_message(&local, &gossip(Some(forger), msg));
+            assert!(matches!(result, Err(MessageRejection::IdentityMismatch { .. })), "{:?}", msg);
+        }
+    }
+
+    #[test]
+    fn parses_known_peers_as_multiaddrs_or_host_port() {
+        assert_eq!(
+            parse_known_peer("192.168.1.5:9000").unwrap(),
+            "/ip4/192.168.1.5/tcp/9000".parse::<Multiaddr>().unwrap()
+        );
+        assert_eq!(
+            parse_known_peer(" /ip4/192.168.1.5/udp/9000/quic-v1 ").unwrap(),
+            "/ip4/192.168.1.5/udp/9000/quic-v1".parse::<Multiaddr>().unwrap()
+        );
+        assert!(parse_known_peer("192.168.1.5").is_err());
+        assert!(parse_known_peer("192.168.1.5:port").is_err());
+        assert!(parse_known_peer("/ip4/192.168.1.5/bogus").is_err());
+    }
+
+    #[test]
+    fn parses_hostnames_ipv6_and_peer_ids() {
+        let with_peer_id = format!("/ip6/::1/tcp/9000/p2p/{}", PeerId::random());
+        let cases = [
+            ("example.com:9000", "/dns/example.com/tcp/9000".to_string()),
+            ("[::1]:9000", "/ip6/::1/tcp/9000".to_string()),
+            ("/dns4/example.com/udp/9000/quic-v1", "/dns4/example.com/udp/9000/quic-v1".to_string()),
+            (with_peer_id.as_str(), with_peer_id.clone()),
+        ];
+        for (spec, expected) in cases {
+            assert_eq!(parse_known_peer(spec).unwrap(), expected.parse::<Multiaddr>().unwrap(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn reports_every_invalid_known_peer() {
+        assert_eq!(parse_known_peers(" 10.0.0.1:9000, ,/ip4/10.0.0.2/udp/9000/quic-v1").unwrap().len(), 2);
+
+        let errors = parse_known_peers("10.0.0.1:9000,nope,/ip4/10.0.0.2/udp/9000,/ip4/10.0.0.3/tcp/1/ws").unwrap_err();
+        let lines: Vec<&str> = errors.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", errors);
+        assert!(lines[0].contains("'nope'"));
+        assert!(lines[1].contains("/udp/9000"));
+        assert!(lines[2].contains("/ws"));
+    }
+
+    #[test]
+    fn parses_bootstrap_addresses() {
+        let peer = PeerId::random();
+        let addrs = parse_bootstrap(&format!(" /ip4/127.0.0.1/tcp/9000/p2p/{} ,", peer)).unwrap();
+        assert_eq!(addrs.len(), 1);
+
+        assert!(parse_bootstrap("").unwrap().is_empty());
+        assert!(parse_bootstrap("/ip4/127.0.0.1/tcp/9000").is_err());
+        assert!(parse_bootstrap("127.0.0.1:9000").is_err());
+    }
+
+    // A port the OS just handed out, free for both TCP and UDP, so parallel test runs don't clash
+    fn free_port() -> u16 {
+        loop {
+            let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = tcp.local_addr().unwrap().port();
+            if std::net::UdpSocket::bind(("127.0.0.1", port)).is_ok() {
+                return port;
+            }
+        }
+    }
+
+    // A node with mDNS off, in the "dht-test" lobby
+    async fn start_test_node(
+        name: &str,
+        port: u16,
+        bootstrap: Vec<Multiaddr>,
+    ) -> (P2PHandle, mpsc::Receiver<ServerMessage>, PathBuf) {
+        let key_file = std::env::temp_dir().join(format!("footsteps-{}-{}.key", name, uuid::Uuid::new_v4()));
+        let config = P2PConfig {
+            node_name: name.to_string(),
+            listen_ip: IpAddr::from([127, 0, 0, 1]),
+            listen_port: port,
+            known_peers: Vec::new(),
+            custom_url: String::new(),
+            key_file: key_file.clone(),
+            verifier: VerifierConfig::default(),
+            extra_image_ids: Vec::new(),
+            proof_distribution: ProofDistribution::default(),
+            lobby: Some("dht-test".to_string()),
+            bootstrap,
+            mdns: false,
+            lobby_lookup_interval: Duration::from_secs(1),
+        };
+        let game_state = Arc::new(Mutex::new(GameState::new()));
+        let (handle, events) = start_p2p_node(config, game_state).await.unwrap();
+        (handle, events, key_file)
+    }
+
+    #[tokio::test]
+    async fn finds_lobby_members_through_the_dht_without_mdns() {
+        let hub_port = free_port();
+        let (hub, _hub_events, hub_key) = start_test_node("hub", hub_port, Vec::new()).await;
+        let hub_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}/p2p/{}", hub_port, hub.peer_id).parse().unwrap();
+        let (alice, _alice_events, alice_key) = start_test_node("alice", free_port(), vec![hub_addr.clone()]).await;
+        let (bob, mut bob_events, bob_key) = start_test_node("bob", free_port(), vec![hub_addr]).await;
+
+        // Bob was only told about the hub, so a connection to alice means the DHT found her
+        let alice_id = alice.peer_id.to_string();
+        let found = tokio::time::timeout(Duration::from_secs(15), async {
+            while let Some(event) = bob_events.recv().await {
+                if matches!(event, ServerMessage::P2pConnection { peer_id, .. } if peer_id == alice_id) {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+
+        for node in [&hub, &alice, &bob] {
+            node.shutdown().await;
+        }
+        for key in [hub_key, alice_key, bob_key] {
+            let _ = fs::remove_file(key);
+        }
+        assert!(matches!(found, Ok(true)), "bob never connected to alice");
+    }
+
+    #[test]
+    fn rejects_undecodable_payload() {
+        let local = PeerId::random();
+        let mut message = gossip(Some(PeerId::random()), &P2PMessage::PlayerLeft { player_id: String::new() });
+        message.data = b"not a message".to_vec();
+
+        assert!(matches!(authenticate_message(&local, &message), Err(MessageRejection::Undecodable(_))));
+    }
 }
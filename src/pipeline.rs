@@ -0,0 +1,419 @@
+// Proving pipeline.
+//
+// Queued key presses are cut into batches on a timer, once enough keys are
+// queued, or when input goes idle. Each batch is executed as soon as it is cut,
+// which checks it against the guest's rules and yields its journal, so the
+// next batch can chain from it without waiting for the proof. Proofs then run
+// on up to `max_in_flight` threads and are published strictly in sequence order.
+use crate::chain::ChainState;
+use crate::prover::{BatchInput, MovementProver};
+use crate::{lobby, p2p, verifier, GameState, KeyInput, StateEvent};
+use footsteps_core::{journal_digest, GameMap, GameParams, GridPos, RevealBeacon};
+use footsteps_methods::FOOTSTEPS_GUEST_ID;
+use risc0_zkvm::{InnerReceipt, Receipt};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// How often the pipeline checks for keys to cut and proofs to publish
+const TICK: Duration = Duration::from_millis(50);
+
+// When batches are cut and how many are proven at once
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    pub batch_interval: Duration,     // Longest queued keys wait before their batch is cut
+    pub max_batch_size: usize,        // Keys per batch; a full batch is cut right away
+    pub idle_flush: Option<Duration>, // Cut early once no key has been pressed for this long
+    pub max_in_flight: usize,         // Batches proven concurrently
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            batch_interval: Duration::from_secs(5),
+            max_batch_size: 32,
+            idle_flush: Some(Duration::from_secs(1)),
+            max_in_flight: 2,
+        }
+    }
+}
+
+impl PipelineConfig {
+    // Whether the queued keys should be cut into a batch now
+    pub fn should_cut(&self, pending: usize, since_cut: Duration, since_input: Duration) -> bool {
+        pending > 0
+            && (pending >= self.max_batch_size
+                || since_cut >= self.batch_interval
+                || self.idle_flush.is_some_and(|idle| since_input >= idle))
+    }
+}
+
+// Releases items in sequence order, holding back any that arrive early
+pub struct InOrder<T> {
+    next: u64,
+    ready: BTreeMap<u64, T>,
+}
+
+impl<T> InOrder<T> {
+    pub fn new(next: u64) -> Self {
+        Self { next, ready: BTreeMap::new() }
+    }
+
+    // Add an item; returns every item that can now be released, in order
+    pub fn push(&mut self, sequence: u64, item: T) -> Vec<T> {
+        if sequence >= self.next {
+            self.ready.insert(sequence, item);
+        }
+        let mut released = Vec::new();
+        while let Some(item) = self.ready.remove(&self.next) {
+            released.push(item);
+            self.next += 1;
+        }
+        released
+    }
+
+    // Drop held items from `sequence` on; they will be produced again
+    pub fn discard_from(&mut self, sequence: u64) {
+        self.ready.retain(|&held, _| held < sequence);
+    }
+}
+
+// A batch cut from the queued keys, along with where it leaves the chain
+struct Batch {
+    input: BatchInput,
+    end: GridPos,
+    journal_digest: [u8; 32], // Digest of the batch's own journal, from execution
+}
+
+impl Batch {
+    fn sequence(&self) -> u64 {
+        self.input.sequence
+    }
+}
+
+// A finished proof, sent back from a proving thread
+struct Proven {
+    batch: Batch,
+    result: Result<Receipt, String>,
+    elapsed: Duration,
+}
+
+pub struct Pipeline {
+    config: PipelineConfig,
+    game_state: Arc<Mutex<GameState>>,
+    player_id: String,
+    map: GameMap,
+    params: GameParams,
+    prover: Arc<dyn MovementProver>,
+    proof_tx: mpsc::Sender<p2p::P2PMessage>,
+    chain_file: PathBuf, // Where the chain is saved after every published batch
+}
+
+impl Pipeline {
+    pub fn new(
+        config: PipelineConfig,
+        game_state: Arc<Mutex<GameState>>,
+        player_id: String,
+        prover: Arc<dyn MovementProver>,
+        proof_tx: mpsc::Sender<p2p::P2PMessage>,
+        chain_file: PathBuf,
+    ) -> Self {
+        let (map, params) = {
+            let state = game_state.lock().unwrap();
+            (state.map.clone(), state.params)
+        };
+        Self { config, game_state, player_id, map, params, prover, proof_tx, chain_file }
+    }
+
+    // Run the pipeline on its own thread
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || self.run())
+    }
+
+    fn run(self) {
+        let (results_tx, results_rx) = std_mpsc::channel::<Proven>();
+        let mut in_flight: HashSet<u64> = HashSet::new();
+        // Batches chained onto a failed proof; no new batch is cut until they finish
+        let mut doomed: HashSet<u64> = HashSet::new();
+        let mut publisher = InOrder::new(self.game_state.lock().unwrap().next_sequence);
+        let mut last_cut = Instant::now();
+
+        loop {
+            thread::sleep(TICK);
+
+            while let Ok(proven) = results_rx.try_recv() {
+                let sequence = proven.batch.sequence();
+                in_flight.remove(&sequence);
+                if doomed.remove(&sequence) {
+                    println!("Dropping proof for batch {}, which followed a failed batch", sequence);
+                    continue;
+                }
+                if let Err(e) = &proven.result {
+                    self.fail(&proven.batch, e);
+                    publisher.discard_from(sequence);
+                    doomed.extend(in_flight.iter().filter(|&&other| other > sequence));
+                    continue;
+                }
+                for proven in publisher.push(sequence, proven) {
+                    self.publish(proven);
+                }
+            }
+
+            let cut = {
+                let mut state = self.game_state.lock().unwrap();
+                if state.processing != !in_flight.is_empty() {
+                    state.processing = !in_flight.is_empty();
+                    state.notify(StateEvent::Local);
+                }
+                let ready = doomed.is_empty()
+                    && in_flight.len() < self.config.max_in_flight
+                    && self.config.should_cut(state.pending_keys.len(), last_cut.elapsed(), state.last_input.elapsed());
+                if ready {
+                    last_cut = Instant::now();
+                    Some(self.cut(&mut state))
+                } else {
+                    None
+                }
+            };
+            let Some(mut batch) = cut else {
+                continue;
+            };
+            // Execute outside the lock so key presses aren't held up
+            match batch.input.execute() {
+                Ok(journal) => batch.journal_digest = journal_digest(&journal),
+                Err(e) => {
+                    println!("Error executing batch {}: {}", batch.sequence(), e);
+                    println!("This may be due to a constraint violation in one of the key presses.");
+                    let mut state = self.game_state.lock().unwrap();
+                    state.set_proof_status("Proof failed: Constraint violation");
+                    revert(&mut state, &batch);
+                    continue;
+                }
+            }
+            {
+                // Chain the next batch onto this one before it is proven
+                let mut state = self.game_state.lock().unwrap();
+                state.proof_start = batch.end;
+                state.last_journal_digest = batch.journal_digest;
+                state.next_sequence = batch.sequence() + 1;
+                state.last_batch_size = batch.input.keys.len();
+                state.set_proof_status("Generating proof...");
+            }
+
+            // Announce the beacon now, while it's still among the newest, since
+            // the proof may take long enough for newer batches to come along
+            let announcement = p2p::P2PMessage::BatchCut {
+                player_id: self.player_id.clone(),
+                epoch: batch.input.epoch,
+                sequence: batch.sequence(),
+                beacon: batch.input.reveal_beacon.clone(),
+            };
+            if let Err(e) = self.proof_tx.blocking_send(announcement) {
+                eprintln!("Error sending batch cut to main context: {:?}", e);
+            }
+
+            in_flight.insert(batch.sequence());
+            let results_tx = results_tx.clone();
+            let prover = Arc::clone(&self.prover);
+            thread::spawn(move || {
+                println!(
+                    "Generating proof for batch {} with the {} prover (this may take a while)...",
+                    batch.sequence(),
+                    prover.name()
+                );
+                let start_time = Instant::now();
+                let result = prover
+                    .prove(&batch.input)
+                    .and_then(|receipt| check_receipt(&batch, receipt, prover.dev_receipts()));
+                let _ = results_tx.send(Proven { batch, result, elapsed: start_time.elapsed() });
+            });
+        }
+    }
+
+    // Cut the next batch from the queued keys
+    fn cut(&self, state: &mut GameState) -> Batch {
+        let count = state.pending_keys.len().min(self.config.max_batch_size);
+        let keys: Vec<KeyInput> = state.pending_keys.drain(..count).collect();
+        let start = state.proof_start;
+        let end = keys.iter().fold(start, |pos, key| {
+            let (dx, dy) = key.offset();
+            pos.offset(dx, dy)
+        });
+        println!(
+            "Cutting batch {} of {} key presses from ({}, {}): {:?}",
+            state.next_sequence, keys.len(), start.x, start.y, keys
+        );
+        Batch {
+            input: BatchInput {
+                keys,
+                start,
+                salt: state.position_salt,
+                map: self.map.clone(),
+                params: self.params,
+                previous_digest: state.last_journal_digest,
+                reveal_beacon: state.reveal_beacon.clone(),
+                epoch: state.epoch,
+                sequence: state.next_sequence,
+                player_id: self.player_id.clone(),
+                match_id: lobby::match_id(state.lobby.as_deref()),
+            },
+            end,
+            journal_digest: [0; 32],
+        }
+    }
+
+    // A batch could not be proven after it executed. Rewind the chain to its
+    // start; batches cut after it are dropped when they come back.
+    fn fail(&self, batch: &Batch, error: &str) {
+        println!("Error generating proof for batch {}: {}", batch.sequence(), error);
+        let mut state = self.game_state.lock().unwrap();
+        state.set_proof_status("Proof failed");
+        state.next_sequence = batch.sequence();
+        state.last_journal_digest = batch.input.previous_digest;
+        revert(&mut state, batch);
+    }
+
+    fn publish(&self, proven: Proven) {
+        let Proven { batch, result, elapsed } = proven;
+        let Ok(receipt) = result else {
+            return;
+        };
+        println!("Proof for batch {} generated in {:.2} seconds", batch.sequence(), elapsed.as_secs_f32());
+        match bincode::serialized_size(&receipt) {
+            Ok(size) => println!(
+                "{} receipt size: {} bytes ({:.2} MB)",
+                verifier::receipt_kind_name(&receipt),
+                size,
+                size as f64 / 1_048_576.0
+            ),
+            Err(e) => eprintln!("Error measuring receipt size: {:?}", e),
+        }
+
+        {
+            let mut state = self.game_state.lock().unwrap();
+            // Flag dev-mode receipts so nobody mistakes them for real proofs
+            state.set_proof_status(if matches!(receipt.inner, InnerReceipt::Fake(_)) {
+                format!("Dev-mode receipt generated in {:.2}s (not a real proof)", elapsed.as_secs_f32())
+            } else {
+                format!("Proof generated in {:.2}s", elapsed.as_secs_f32())
+            });
+            // The end of this batch is now the last verified position
+            state.last_verified = batch.end;
+            // Peers may seed their reveals from it
+            state.remember_batch(RevealBeacon {
+                player_id: self.player_id.clone(),
+                epoch: batch.input.epoch,
+                sequence: batch.input.sequence,
+                journal_digest: batch.journal_digest,
+            });
+        }
+
+        // Send the proof to the p2p network for other players to verify
+        let p2p_msg = p2p::P2PMessage::Proof {
+            player_id: self.player_id.clone(),
+            receipt,
+            ImageID: FOOTSTEPS_GUEST_ID,
+        };
+        if let Err(e) = self.proof_tx.blocking_send(p2p_msg) {
+            eprintln!("Error sending proof to main context: {:?}", e);
+        }
+
+        // The next run continues the chain from this batch
+        let chain = ChainState {
+            salt: batch.input.salt,
+            epoch: batch.input.epoch,
+            end: batch.end,
+            journal_digest: batch.journal_digest,
+        };
+        if let Err(e) = chain.save(&self.chain_file) {
+            eprintln!("Error saving chain: {}", e);
+        }
+    }
+}
+
+// A receipt goes out under our name, so whichever backend made it, it must
+// verify and prove exactly the batch we executed
+fn check_receipt(batch: &Batch, receipt: Receipt, accept_dev_receipts: bool) -> Result<Receipt, String> {
+    if journal_digest(&receipt.journal.bytes) != batch.journal_digest {
+        return Err("receipt journal doesn't match the executed batch".to_string());
+    }
+    verifier::verify_receipt(&receipt, FOOTSTEPS_GUEST_ID, accept_dev_receipts)
+        .map_err(|e| format!("receipt doesn't verify: {:?}", e))?;
+    Ok(receipt)
+}
+
+// Move the player back to where a rejected batch started. The next proof must
+// start there too, so moves queued on top of the batch are dropped.
+fn revert(state: &mut GameState, batch: &Batch) {
+    let start = batch.input.start;
+    state.position = start;
+    state.proof_start = start;
+    state.pending_keys.clear();
+    state.notify(StateEvent::Local);
+    println!("Position reverted to ({}, {})", start.x, start.y);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::fake_receipt;
+    use crate::prover::tests::input;
+
+    #[test]
+    fn cuts_full_late_or_idle_batches() {
+        let config = PipelineConfig {
+            batch_interval: Duration::from_secs(5),
+            max_batch_size: 4,
+            idle_flush: Some(Duration::from_secs(1)),
+            max_in_flight: 2,
+        };
+        let short = Duration::from_millis(100);
+        assert!(!config.should_cut(0, Duration::from_secs(60), Duration::from_secs(60)));
+        assert!(!config.should_cut(3, short, short));
+        assert!(config.should_cut(4, short, short));
+        assert!(config.should_cut(1, Duration::from_secs(5), short));
+        assert!(config.should_cut(1, short, Duration::from_secs(1)));
+
+        let no_idle = PipelineConfig { idle_flush: None, ..config };
+        assert!(!no_idle.should_cut(1, short, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn releases_in_sequence_order() {
+        let mut publisher = InOrder::new(3);
+        assert!(publisher.push(5, "five").is_empty());
+        assert!(publisher.push(4, "four").is_empty());
+        assert_eq!(publisher.push(3, "three"), vec!["three", "four", "five"]);
+        assert!(publisher.push(2, "stale").is_empty());
+        assert_eq!(publisher.push(6, "six"), vec!["six"]);
+    }
+
+    #[test]
+    fn rejects_receipts_for_other_batches() {
+        let receipt = fake_receipt();
+        let mut batch = Batch {
+            input: input(vec![KeyInput::Up]),
+            end: GridPos::default(),
+            journal_digest: journal_digest(&receipt.journal.bytes),
+        };
+        let error = check_receipt(&batch, receipt.clone(), false).unwrap_err();
+        assert!(error.contains("DevReceipt"), "{}", error);
+
+        batch.journal_digest[0] ^= 1;
+        let error = check_receipt(&batch, receipt, true).unwrap_err();
+        assert!(error.contains("doesn't match"), "{}", error);
+    }
+
+    #[test]
+    fn discards_held_items_after_a_failure() {
+        let mut publisher = InOrder::new(0);
+        assert!(publisher.push(1, "old one").is_empty());
+        assert!(publisher.push(2, "old two").is_empty());
+        publisher.discard_from(1);
+        assert_eq!(publisher.push(0, "zero"), vec!["zero"]);
+        assert_eq!(publisher.push(1, "new one"), vec!["new one"]);
+    }
+}
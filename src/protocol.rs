@@ -0,0 +1,263 @@
+// WebSocket protocol between the node and the frontend.
+//
+// Every message is a JSON object tagged by "type". A client opens with
+// `hello` carrying its protocol version; the node answers `welcome` and a
+// snapshot of the game, or an `error` and closes if the versions differ.
+// After that the node pushes deltas as the game changes, and a fresh snapshot
+// when the client asks to `resync` or falls too far behind.
+// Input the node can't parse gets an `error` reply instead of being dropped.
+//
+// The TypeScript types in app/app/hooks/protocol.ts are generated from these
+// definitions by `cargo test`, so the frontend can't drift from the node.
+use crate::lobby::LobbyListing;
+use crate::{KeyInput, RemotePlayer, RosterEntry};
+use footsteps_core::GameMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Bumped whenever a message changes incompatibly
+pub const PROTOCOL_VERSION: u32 = 2;
+
+// Messages sent by the frontend
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
+pub enum ClientMessage {
+    // Must be the first message on a connection
+    Hello { version: u32 },
+    KeyPress { key: Key },
+    ListLobbies,
+    // Without a name the node makes up a short code to share
+    CreateLobby {
+        #[serde(default)]
+        #[cfg_attr(test, ts(optional))]
+        name: Option<String>,
+    },
+    JoinLobby { name: String },
+    LeaveLobby,
+    // Ask for a full snapshot, e.g. after the client lost track of the deltas
+    Resync,
+}
+
+// Keys the frontend can send
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "lowercase")]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    // Moves by 3 to exercise the guest's constraint check
+    Test,
+}
+
+impl From<Key> for KeyInput {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Up => KeyInput::Up,
+            Key::Down => KeyInput::Down,
+            Key::Left => KeyInput::Left,
+            Key::Right => KeyInput::Right,
+            Key::Test => KeyInput::TestConstraint,
+        }
+    }
+}
+
+// Messages sent by the node
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(tag = "type", rename_all = "snake_case", rename_all_fields = "camelCase")]
+pub enum ServerMessage {
+    // Answer to a supported `hello`
+    Welcome { version: u32, node_name: String },
+    // Full snapshot, sent after the handshake and on resync
+    StateUpdate(StateSnapshot),
+    // Our own player changed
+    LocalUpdate(LocalState),
+    // A remote player's latest verified trail segment, status or presence changed
+    PlayerUpdate { peer_id: String, player: RemotePlayer },
+    // Map every player moves on, sent once after the handshake
+    MapInfo {
+        #[cfg_attr(test, ts(type = "{ width: number, height: number, walls: Array<boolean> }"))]
+        map: GameMap,
+    },
+    // Players currently in our lobby
+    RosterUpdate { roster: Vec<RosterEntry> },
+    // Lobby we're in and the open lobbies we know of
+    LobbyUpdate {
+        current: Option<String>,
+        lobbies: Vec<LobbyListing>,
+    },
+    LobbyError { message: String },
+    // Another node shared its name and URL
+    NodeInfo {
+        peer_id: String,
+        name: String,
+        custom_url: String,
+    },
+    // A peer connected to our node
+    P2pConnection {
+        peer_id: String,
+        address: String,
+        is_incoming: bool,
+        #[cfg_attr(test, ts(type = "number"))]
+        timestamp: u64,
+    },
+    Error { code: ErrorCode, message: String },
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error { code, message: message.into() }
+    }
+
+    pub fn to_json(&self) -> String {
+        // Every field serializes to JSON, so this can't fail
+        serde_json::to_string(self).expect("server messages serialize to JSON")
+    }
+}
+
+// What went wrong with a client's message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The client's protocol version isn't ours; the connection is closed
+    UnsupportedVersion,
+    // The first message wasn't `hello`; the connection is closed
+    HandshakeRequired,
+    // The message wasn't valid JSON or not a known client message
+    BadRequest,
+}
+
+// Our player's state and the verified state of everyone else
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "camelCase")]
+pub struct StateSnapshot {
+    pub position: Position,
+    pub proof_status: String,
+    pub processing: bool,
+    #[cfg_attr(test, ts(type = "number"))]
+    pub last_batch_size: usize,
+    pub players: HashMap<String, RemotePlayer>, // Remote players keyed by PeerId
+    pub node_name: String,
+}
+
+// Our own player, as shown next to the board
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+#[serde(rename_all = "camelCase")]
+pub struct LocalState {
+    pub position: Position,
+    pub proof_status: String,
+    pub processing: bool,
+    #[cfg_attr(test, ts(type = "number"))]
+    pub last_batch_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(test, derive(ts_rs::TS))]
+#[cfg_attr(test, ts(export, export_to = "../app/app/hooks/protocol.ts"))]
+pub struct Position {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_client_messages() {
+        let parse = |value: serde_json::Value| serde_json::from_value::<ClientMessage>(value);
+        assert_eq!(parse(json!({ "type": "hello", "version": 1 })).unwrap(), ClientMessage::Hello { version: 1 });
+        assert_eq!(
+            parse(json!({ "type": "key_press", "key": "test" })).unwrap(),
+            ClientMessage::KeyPress { key: Key::Test }
+        );
+        assert_eq!(parse(json!({ "type": "create_lobby" })).unwrap(), ClientMessage::CreateLobby { name: None });
+        assert_eq!(parse(json!({ "type": "leave_lobby" })).unwrap(), ClientMessage::LeaveLobby);
+        assert_eq!(parse(json!({ "type": "resync" })).unwrap(), ClientMessage::Resync);
+
+        assert!(parse(json!({ "type": "key_press", "key": "jump" })).is_err());
+        assert!(parse(json!({ "type": "join_lobby" })).is_err());
+        assert!(parse(json!({ "type": "teleport" })).is_err());
+        assert!(parse(json!({ "key": "up" })).is_err());
+    }
+
+    #[test]
+    fn tags_server_messages() {
+        let welcome = serde_json::to_value(ServerMessage::Welcome { version: PROTOCOL_VERSION, node_name: "alice".into() });
+        assert_eq!(welcome.unwrap(), json!({ "type": "welcome", "version": PROTOCOL_VERSION, "nodeName": "alice" }));
+
+        let error = serde_json::to_value(ServerMessage::error(ErrorCode::BadRequest, "nope")).unwrap();
+        assert_eq!(error, json!({ "type": "error", "code": "bad_request", "message": "nope" }));
+
+        // Fields are camelCase in every message
+        let connection = ServerMessage::P2pConnection {
+            peer_id: "12D3KooWPeer".into(),
+            address: "/ip4/10.0.0.2/tcp/9000".into(),
+            is_incoming: true,
+            timestamp: 7,
+        };
+        assert_eq!(
+            serde_json::to_value(connection).unwrap(),
+            json!({
+                "type": "p2p_connection",
+                "peerId": "12D3KooWPeer",
+                "address": "/ip4/10.0.0.2/tcp/9000",
+                "isIncoming": true,
+                "timestamp": 7,
+            })
+        );
+
+        let info = ServerMessage::NodeInfo { peer_id: "12D3KooWPeer".into(), name: "bob".into(), custom_url: "https://bob".into() };
+        let info = serde_json::to_value(info).unwrap();
+        assert_eq!(info["customUrl"], "https://bob");
+
+        let state = ServerMessage::StateUpdate(StateSnapshot {
+            position: Position { x: 1, y: 2 },
+            proof_status: "Waiting for input".into(),
+            processing: false,
+            last_batch_size: 0,
+            players: HashMap::new(),
+            node_name: "alice".into(),
+        });
+        let state = serde_json::to_value(state).unwrap();
+        assert_eq!(state["type"], "state_update");
+        assert_eq!(state["position"], json!({ "x": 1, "y": 2 }));
+        assert_eq!(state["proofStatus"], "Waiting for input");
+
+        let local = ServerMessage::LocalUpdate(LocalState {
+            position: Position { x: 3, y: 4 },
+            proof_status: "Generating proof...".into(),
+            processing: true,
+            last_batch_size: 2,
+        });
+        assert_eq!(
+            serde_json::to_value(local).unwrap(),
+            json!({
+                "type": "local_update",
+                "position": { "x": 3, "y": 4 },
+                "proofStatus": "Generating proof...",
+                "processing": true,
+                "lastBatchSize": 2,
+            })
+        );
+
+        let player = ServerMessage::PlayerUpdate { peer_id: "12D3KooWPeer".into(), player: RemotePlayer::default() };
+        let player = serde_json::to_value(player).unwrap();
+        assert_eq!(player["type"], "player_update");
+        assert_eq!(player["peerId"], "12D3KooWPeer");
+        assert_eq!(player["player"]["trail"], json!([]));
+    }
+}
@@ -0,0 +1,432 @@
+// Proving backends.
+//
+// The pipeline hands every batch to a MovementProver. The local backend proves
+// on this machine. Dev mode only executes the guest and returns a fake receipt,
+// which peers reject unless they opt in to dev receipts. The remote backend
+// sends the batch over TCP to a proving server, such as another node started
+// with a prover listen address, so slow machines can offload proving.
+use crate::KeyInput;
+use footsteps_core::{GameMap, GameParams, GridPos, RevealBeacon, SALT_LEN};
+use footsteps_methods::{FOOTSTEPS_GUEST_ELF, FOOTSTEPS_GUEST_ID};
+use risc0_zkvm::{
+    default_executor, default_prover, ExecutorEnv, FakeReceipt, InnerReceipt, ProverOpts, Receipt, ReceiptClaim,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Largest proving request the server reads. A batch is some key presses and
+// a small map, far below this.
+const MAX_REQUEST_LEN: usize = 256 * 1024;
+
+// Largest response read from a proving server; composite receipts are big
+const MAX_RESPONSE_LEN: usize = 64 * 1024 * 1024;
+
+// Requests the server proves at once; any more are told it is busy
+const MAX_CONCURRENT_JOBS: usize = 2;
+
+// How long the server waits for a client to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a remote prover may take to answer
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Everything the guest reads for one batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchInput {
+    pub keys: Vec<KeyInput>,
+    pub start: GridPos,
+    pub salt: [u8; SALT_LEN],
+    pub map: GameMap,
+    pub params: GameParams,
+    pub previous_digest: [u8; 32],
+    pub reveal_beacon: Option<RevealBeacon>,
+    pub epoch: u64,
+    pub sequence: u64,
+    pub player_id: String,
+    pub match_id: [u8; 32],
+}
+
+impl BatchInput {
+    // Guest environment, written in the order the guest reads it
+    pub fn env(&self) -> Result<ExecutorEnv<'static>, String> {
+        ExecutorEnv::builder()
+            .write(&self.keys)
+            .and_then(|b| b.write(&self.start))
+            .and_then(|b| b.write(&self.salt))
+            .and_then(|b| b.write(&self.map))
+            .and_then(|b| b.write(&self.params))
+            .and_then(|b| b.write(&self.previous_digest))
+            .and_then(|b| b.write(&self.reveal_beacon))
+            .and_then(|b| b.write(&self.epoch))
+            .and_then(|b| b.write(&self.sequence))
+            .and_then(|b| b.write(&self.player_id))
+            .and_then(|b| b.write(&self.match_id))
+            .and_then(|b| b.build())
+            .map_err(|e| format!("{:?}", e))
+    }
+
+    // Run the guest without proving; returns the journal
+    pub fn execute(&self) -> Result<Vec<u8>, String> {
+        let session = default_executor()
+            .execute(self.env()?, FOOTSTEPS_GUEST_ELF)
+            .map_err(|e| format!("{:?}", e))?;
+        Ok(session.journal.bytes)
+    }
+}
+
+// Turns a batch into a receipt for the footsteps guest
+pub trait MovementProver: Send + Sync {
+    // Backend name for logs
+    fn name(&self) -> String;
+
+    fn prove(&self, input: &BatchInput) -> Result<Receipt, String>;
+
+    // Whether this backend's receipts are fake ones
+    fn dev_receipts(&self) -> bool {
+        false
+    }
+}
+
+// Proves on this machine
+pub struct LocalProver {
+    opts: ProverOpts,
+}
+
+impl LocalProver {
+    pub fn new(opts: ProverOpts) -> Self {
+        Self { opts }
+    }
+}
+
+impl MovementProver for LocalProver {
+    fn name(&self) -> String {
+        "local".to_string()
+    }
+
+    fn prove(&self, input: &BatchInput) -> Result<Receipt, String> {
+        default_prover()
+            .prove_with_opts(input.env()?, FOOTSTEPS_GUEST_ELF, &self.opts)
+            .map(|info| info.receipt)
+            .map_err(|e| format!("{:?}", e))
+    }
+}
+
+// Executes the guest and wraps its journal in a fake receipt. Only for
+// development: the receipt proves nothing and strict peers reject it.
+pub struct DevProver;
+
+impl MovementProver for DevProver {
+    fn name(&self) -> String {
+        "dev".to_string()
+    }
+
+    fn prove(&self, input: &BatchInput) -> Result<Receipt, String> {
+        let journal = input.execute()?;
+        let claim = ReceiptClaim::ok(FOOTSTEPS_GUEST_ID, journal.clone());
+        Ok(Receipt::new(InnerReceipt::Fake(FakeReceipt::new(claim)), journal))
+    }
+
+    fn dev_receipts(&self) -> bool {
+        true
+    }
+}
+
+// Sends batches to a proving server. The server sees our moves and position
+// salt, so only point this at a machine you trust.
+pub struct RemoteProver {
+    addr: String,
+}
+
+impl RemoteProver {
+    pub fn new(addr: String) -> Self {
+        Self { addr }
+    }
+}
+
+impl MovementProver for RemoteProver {
+    fn name(&self) -> String {
+        format!("remote ({})", self.addr)
+    }
+
+    fn prove(&self, input: &BatchInput) -> Result<Receipt, String> {
+        let mut stream = TcpStream::connect(&self.addr)
+            .map_err(|e| format!("Failed to connect to prover at {}: {}", self.addr, e))?;
+        stream.set_read_timeout(Some(REMOTE_TIMEOUT)).map_err(|e| e.to_string())?;
+        write_frame(&mut stream, input)?;
+        let response: Result<Receipt, String> = read_frame(&mut stream, MAX_RESPONSE_LEN)?;
+        response.map_err(|e| format!("Prover at {} failed: {}", self.addr, e))
+    }
+}
+
+// Proving backend selected on the command line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProverBackend {
+    Local,
+    Dev,
+    Remote(String), // host:port of a proving server
+}
+
+impl std::str::FromStr for ProverBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "local" => Ok(ProverBackend::Local),
+            "dev" => Ok(ProverBackend::Dev),
+            other => match other.strip_prefix("remote:") {
+                Some(addr) if !addr.is_empty() => Ok(ProverBackend::Remote(addr.to_string())),
+                _ => Err(format!(
+                    "Unknown prover backend '{}', expected local, dev or remote:<host:port>",
+                    other
+                )),
+            },
+        }
+    }
+}
+
+impl ProverBackend {
+    pub fn build(&self, opts: ProverOpts) -> Arc<dyn MovementProver> {
+        match self {
+            ProverBackend::Local => Arc::new(LocalProver::new(opts)),
+            ProverBackend::Dev => Arc::new(DevProver),
+            ProverBackend::Remote(addr) => Arc::new(RemoteProver::new(addr.clone())),
+        }
+    }
+}
+
+// Answer proving requests from remote nodes. Clients aren't authenticated and
+// get whatever they ask proven, so only bind this to a trusted interface, such
+// as localhost or a private network of your own machines. Each connection is
+// read on its own thread, so a slow client holds up nobody else, and up to
+// MAX_CONCURRENT_JOBS requests are proven at once; the rest are turned away.
+pub fn serve(listener: TcpListener, prover: Arc<dyn MovementProver>) {
+    let jobs = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Error accepting prover connection: {:?}", e);
+                continue;
+            }
+        };
+        let prover = Arc::clone(&prover);
+        let jobs = Arc::clone(&jobs);
+        thread::spawn(move || answer(stream, prover.as_ref(), &jobs));
+    }
+}
+
+// Read one proving request and answer it, unless MAX_CONCURRENT_JOBS are already running
+fn answer(mut stream: TcpStream, prover: &dyn MovementProver, jobs: &Arc<AtomicUsize>) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    let input: BatchInput = match stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(|e| e.to_string())
+        .and_then(|_| read_frame(&mut stream, MAX_REQUEST_LEN))
+    {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Bad proving request from {}: {}", peer, e);
+            return;
+        }
+    };
+    if jobs.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_JOBS {
+        jobs.fetch_sub(1, Ordering::SeqCst);
+        println!("Turning away batch {} from {}: already proving {} batches", input.sequence, peer, MAX_CONCURRENT_JOBS);
+        let busy: Result<Receipt, String> = Err("prover is busy".to_string());
+        let _ = write_frame(&mut stream, &busy);
+        return;
+    }
+
+    let slot = JobSlot(Arc::clone(jobs));
+    println!("Proving batch {} for {} with the {} prover", input.sequence, peer, prover.name());
+    let result = prover.prove(&input);
+    drop(slot);
+    if let Err(e) = write_frame(&mut stream, &result) {
+        eprintln!("Error answering proving request from {}: {}", peer, e);
+    }
+}
+
+// Frees a proving slot once its job ends, even if the prover panics
+struct JobSlot(Arc<AtomicUsize>);
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Length-prefixed bincode
+fn write_frame<T: Serialize>(stream: &mut TcpStream, value: &T) -> Result<(), String> {
+    let payload = bincode::serialize(value).map_err(|e| e.to_string())?;
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .and_then(|_| stream.write_all(&payload))
+        .map_err(|e| e.to_string())
+}
+
+fn read_frame<T: DeserializeOwned>(stream: &mut TcpStream, max_len: usize) -> Result<T, String> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).map_err(|e| e.to_string())?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(format!("frame of {} bytes is too large", len));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).map_err(|e| e.to_string())?;
+    bincode::deserialize(&payload).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::codec::tests::fake_receipt;
+    use std::sync::{mpsc, Mutex};
+    use std::time::Instant;
+
+    // Stands in for a proving box without running the guest
+    struct MockProver;
+
+    impl MovementProver for MockProver {
+        fn name(&self) -> String {
+            "mock".to_string()
+        }
+
+        fn prove(&self, input: &BatchInput) -> Result<Receipt, String> {
+            if input.keys.contains(&KeyInput::TestConstraint) {
+                return Err("constraint violated".to_string());
+            }
+            Ok(fake_receipt())
+        }
+    }
+
+    // Holds every proof until the test lets it through
+    struct GatedProver {
+        started: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl MovementProver for GatedProver {
+        fn name(&self) -> String {
+            "gated".to_string()
+        }
+
+        fn prove(&self, _input: &BatchInput) -> Result<Receipt, String> {
+            self.started.lock().unwrap().send(()).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+            Ok(fake_receipt())
+        }
+    }
+
+    fn mock_server() -> RemoteProver {
+        serve_with(Arc::new(MockProver))
+    }
+
+    fn serve_with(prover: Arc<dyn MovementProver>) -> RemoteProver {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || serve(listener, prover));
+        RemoteProver::new(addr)
+    }
+
+    pub(crate) fn input(keys: Vec<KeyInput>) -> BatchInput {
+        BatchInput {
+            keys,
+            start: GridPos::default(),
+            salt: [0; SALT_LEN],
+            map: GameMap::default(),
+            params: GameParams::default(),
+            previous_digest: [0; 32],
+            reveal_beacon: None,
+            epoch: 0,
+            sequence: 0,
+            player_id: String::new(),
+            match_id: [0; 32],
+        }
+    }
+
+    #[test]
+    fn proves_through_a_remote_server() {
+        let remote = mock_server();
+        let receipt = remote.prove(&input(vec![KeyInput::Up, KeyInput::Right])).unwrap();
+        assert_eq!(receipt.journal.bytes, fake_receipt().journal.bytes);
+
+        let error = remote.prove(&input(vec![KeyInput::TestConstraint])).unwrap_err();
+        assert!(error.contains("constraint violated"), "{}", error);
+    }
+
+    #[test]
+    fn turns_away_requests_beyond_its_capacity() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let remote = Arc::new(serve_with(Arc::new(GatedProver {
+            started: Mutex::new(started_tx),
+            release: Mutex::new(release_rx),
+        })));
+
+        let clients: Vec<_> = (0..MAX_CONCURRENT_JOBS)
+            .map(|_| {
+                let remote = Arc::clone(&remote);
+                thread::spawn(move || remote.prove(&input(vec![KeyInput::Up])))
+            })
+            .collect();
+        for _ in 0..MAX_CONCURRENT_JOBS {
+            started_rx.recv().unwrap();
+        }
+        let error = remote.prove(&input(vec![KeyInput::Up])).unwrap_err();
+        assert!(error.contains("busy"), "{}", error);
+
+        for _ in 0..MAX_CONCURRENT_JOBS {
+            release_tx.send(()).unwrap();
+        }
+        for client in clients {
+            assert!(client.join().unwrap().is_ok());
+        }
+        // Finished jobs free their slots
+        release_tx.send(()).unwrap();
+        assert!(remote.prove(&input(vec![KeyInput::Up])).is_ok());
+    }
+
+    #[test]
+    fn serves_others_while_a_client_stalls() {
+        let remote = mock_server();
+        // Connects and never sends its request
+        let _stalled = TcpStream::connect(&remote.addr).unwrap();
+        let start = Instant::now();
+        assert!(remote.prove(&input(vec![KeyInput::Up])).is_ok());
+        assert!(start.elapsed() < REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn rejects_oversized_requests() {
+        let remote = mock_server();
+        let mut request = input(vec![KeyInput::Up]);
+        request.map = GameMap::from_ascii(&".".repeat(MAX_REQUEST_LEN + 1)).unwrap();
+        assert!(remote.prove(&request).is_err());
+        // The server carries on
+        assert!(remote.prove(&input(vec![KeyInput::Up])).is_ok());
+    }
+
+    #[test]
+    fn reports_an_unreachable_remote_prover() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let error = RemoteProver::new(addr.clone()).prove(&input(Vec::new())).unwrap_err();
+        assert!(error.contains(&addr), "{}", error);
+    }
+
+    #[test]
+    fn parses_prover_backends() {
+        assert_eq!("local".parse::<ProverBackend>().unwrap(), ProverBackend::Local);
+        assert_eq!("dev".parse::<ProverBackend>().unwrap(), ProverBackend::Dev);
+        assert_eq!(
+            "remote:prover.lan:7000".parse::<ProverBackend>().unwrap(),
+            ProverBackend::Remote("prover.lan:7000".to_string())
+        );
+        assert!("remote:".parse::<ProverBackend>().is_err());
+        assert!("gpu".parse::<ProverBackend>().is_err());
+    }
+}
@@ -0,0 +1,132 @@
+// Request-response protocol for fetching full receipts.
+//
+// In announce mode gossipsub only carries a small ProofAnnouncement. Peers
+// that want the proof ask whoever relayed the announcement, or the prover
+// itself, for the receipt by hash. A peer that finds batches missing from a
+// player's chain asks for them by epoch and sequence number the same way.
+// Every node keeps the receipts it proved or verified so it can serve them onward.
+use libp2p::StreamProtocol;
+use risc0_zkvm::Receipt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/footsteps/receipt/2");
+
+// Receipts kept for serving to other peers
+const CACHE_CAPACITY: usize = 64;
+
+// One batch in a player's chain
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BatchId {
+    pub player_id: String,
+    pub epoch: u64,
+    pub sequence: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptRequest {
+    Hash([u8; 32]), // An announced receipt
+    Batch(BatchId), // A batch missing from a player's chain
+}
+
+impl std::fmt::Display for ReceiptRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReceiptRequest::Hash(hash) => write!(f, "receipt {:02x}{:02x}{:02x}{:02x}", hash[0], hash[1], hash[2], hash[3]),
+            ReceiptRequest::Batch(batch) => {
+                write!(f, "batch {} of epoch {} from {}", batch.sequence, batch.epoch, batch.player_id)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ReceiptResponse {
+    Found(Receipt),
+    NotFound,
+}
+
+// Content hash a receipt is announced and requested by
+pub fn receipt_hash(receipt: &Receipt) -> Result<[u8; 32], bincode::Error> {
+    Ok(Sha256::digest(bincode::serialize(receipt)?).into())
+}
+
+// Bounded cache of receipts by hash and by batch; the oldest entry is evicted first
+#[derive(Default)]
+pub struct ReceiptCache {
+    receipts: HashMap<[u8; 32], (BatchId, Receipt)>,
+    batches: HashMap<BatchId, [u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl ReceiptCache {
+    pub fn insert(&mut self, hash: [u8; 32], batch: BatchId, receipt: Receipt) {
+        if self.receipts.contains_key(&hash) {
+            return;
+        }
+        self.batches.insert(batch.clone(), hash);
+        self.receipts.insert(hash, (batch, receipt));
+        self.order.push_back(hash);
+        while self.order.len() > CACHE_CAPACITY {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            if let Some((batch, _)) = self.receipts.remove(&oldest) {
+                // Unless a later receipt for the same batch replaced it
+                if self.batches.get(&batch) == Some(&oldest) {
+                    self.batches.remove(&batch);
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, hash: &[u8; 32]) -> Option<&Receipt> {
+        self.receipts.get(hash).map(|(_, receipt)| receipt)
+    }
+
+    pub fn get_batch(&self, batch: &BatchId) -> Option<&Receipt> {
+        self.batches.get(batch).and_then(|hash| self.get(hash))
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.receipts.contains_key(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::fake_receipt;
+
+    fn batch(sequence: usize) -> BatchId {
+        BatchId { player_id: "alice".to_string(), epoch: 0, sequence: sequence as u64 }
+    }
+
+    #[test]
+    fn evicts_oldest_receipts_past_capacity() {
+        let mut cache = ReceiptCache::default();
+        for i in 0..=CACHE_CAPACITY {
+            cache.insert([i as u8; 32], batch(i), fake_receipt());
+        }
+        assert!(!cache.contains(&[0; 32]));
+        assert!(cache.get_batch(&batch(0)).is_none());
+        assert!(cache.contains(&[1; 32]));
+        assert!(cache.get_batch(&batch(1)).is_some());
+        assert!(cache.get(&[CACHE_CAPACITY as u8; 32]).is_some());
+    }
+
+    #[test]
+    fn finds_receipts_by_batch() {
+        let mut cache = ReceiptCache::default();
+        cache.insert([1; 32], batch(1), fake_receipt());
+        assert!(cache.get_batch(&batch(1)).is_some());
+        assert!(cache.get_batch(&batch(2)).is_none());
+        assert!(cache.get_batch(&BatchId { epoch: 1, ..batch(1) }).is_none());
+    }
+
+    #[test]
+    fn receipt_hash_is_stable() {
+        assert_eq!(receipt_hash(&fake_receipt()).unwrap(), receipt_hash(&fake_receipt()).unwrap());
+    }
+}
@@ -0,0 +1,255 @@
+// Proof verification on a pool of blocking workers, off the swarm's event loop.
+use footsteps_core::{journal_digest, Outputs};
+use libp2p::{gossipsub::MessageId, PeerId};
+use risc0_zkvm::sha::{Digestible, Impl};
+use risc0_zkvm::{InnerReceipt, Receipt, ReceiptClaim};
+use std::collections::{HashSet, VecDeque};
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+// Limits for the proof verification worker pool
+#[derive(Debug, Clone, Copy)]
+pub struct VerifierConfig {
+    pub workers: usize,    // Receipts verified concurrently
+    pub max_queued: usize, // Proofs waiting for a worker before new ones are turned away
+    pub accept_dev_receipts: bool, // Accept fake receipts from dev-mode provers
+}
+
+impl Default for VerifierConfig {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+        Self {
+            workers: (cpus / 2).max(1),
+            max_queued: 32,
+            accept_dev_receipts: false,
+        }
+    }
+}
+
+// A received proof waiting to be verified
+pub struct VerifyJob {
+    pub player_id: String,
+    pub receipt: Receipt,
+    pub image_id: [u32; 8],
+    // Gossipsub messages held back until verified, each with the peer that
+    // relayed it: the proof itself, or every chunk it arrived in
+    pub messages: Vec<(MessageId, PeerId)>,
+}
+
+// Why a proof could not be turned into verified outputs
+#[derive(Debug)]
+pub enum VerifyError {
+    Receipt(String),
+    Journal(String),
+    DevReceipt, // Fake receipt from a dev-mode prover, and we don't accept those
+}
+
+// Result of a verification job, sent back to the swarm event loop
+pub struct VerifyOutcome {
+    pub player_id: String,
+    pub image_id: [u32; 8],
+    pub messages: Vec<(MessageId, PeerId)>,
+    pub journal_digest: [u8; 32],
+    pub result: Result<Outputs, VerifyError>,
+    pub receipt: Receipt, // Handed back so verified receipts can be cached and served
+}
+
+// Parse a guest image ID from its 64 character hex form, as printed by `image_id_hex`
+pub fn parse_image_id(hex: &str) -> Result<[u32; 8], String> {
+    let hex = hex.trim().trim_start_matches("0x");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("image ID must be 64 hex characters, got '{}'", hex));
+    }
+
+    let mut image_id = [0u32; 8];
+    for (i, word) in image_id.iter_mut().enumerate() {
+        let mut bytes = [0u8; 4];
+        for (j, byte) in bytes.iter_mut().enumerate() {
+            let start = (i * 4 + j) * 2;
+            *byte = u8::from_str_radix(&hex[start..start + 2], 16)
+                .map_err(|e| format!("invalid image ID '{}': {}", hex, e))?;
+        }
+        *word = u32::from_le_bytes(bytes);
+    }
+    Ok(image_id)
+}
+
+// Hex form of a guest image ID, matching risc0's digest formatting
+pub fn image_id_hex(image_id: &[u32; 8]) -> String {
+    image_id
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Name of a receipt's kind, for logs
+pub fn receipt_kind_name(receipt: &Receipt) -> &'static str {
+    match &receipt.inner {
+        InnerReceipt::Composite(_) => "Composite",
+        InnerReceipt::Succinct(_) => "Succinct",
+        InnerReceipt::Groth16(_) => "Groth16",
+        InnerReceipt::Fake(_) => "Fake",
+        _ => "Unknown",
+    }
+}
+
+// Verify a receipt of any kind against an image ID and decode its journal.
+// Fake receipts prove nothing; when dev receipts are accepted we only check
+// that the receipt claims a successful run of the image with this journal.
+pub fn verify_receipt(receipt: &Receipt, image_id: [u32; 8], accept_dev_receipts: bool) -> Result<Outputs, VerifyError> {
+    let start_time = Instant::now();
+    match &receipt.inner {
+        InnerReceipt::Fake(_) if !accept_dev_receipts => return Err(VerifyError::DevReceipt),
+        InnerReceipt::Fake(fake) => {
+            let expected = ReceiptClaim::ok(image_id, receipt.journal.bytes.clone());
+            if fake.claim.digest::<Impl>() != expected.digest::<Impl>() {
+                return Err(VerifyError::Receipt("dev receipt claims a different image or journal".to_string()));
+            }
+        }
+        _ => receipt
+            .verify(image_id)
+            .map_err(|e| VerifyError::Receipt(format!("{:?}", e)))?,
+    }
+    println!(
+        "{} receipt verified in {:.2}s",
+        receipt_kind_name(receipt),
+        start_time.elapsed().as_secs_f32()
+    );
+    receipt
+        .journal
+        .decode()
+        .map_err(|e| VerifyError::Journal(format!("{:?}", e)))
+}
+
+// Most proofs one player may have waiting, so a flood from one player can't
+// crowd out everyone else's
+const MAX_QUEUED_PER_PLAYER: usize = 4;
+
+// Bounded pool of blocking verification workers.
+// Proofs from one player are verified one at a time and in arrival order, so
+// results come back in the order their position chain expects. Queued proofs
+// are never dropped; once the queue or the player's share of it is full, new
+// proofs are turned away and the sender has to try again later.
+pub struct VerifierPool {
+    config: VerifierConfig,
+    queue: VecDeque<VerifyJob>,
+    in_flight: HashSet<String>, // Players with a proof currently being verified
+    results_tx: mpsc::Sender<VerifyOutcome>,
+}
+
+impl VerifierPool {
+    pub fn new(config: VerifierConfig) -> (Self, mpsc::Receiver<VerifyOutcome>) {
+        let (results_tx, results_rx) = mpsc::channel(config.workers.max(1) * 2);
+        let pool = Self {
+            config,
+            queue: VecDeque::new(),
+            in_flight: HashSet::new(),
+            results_tx,
+        };
+        (pool, results_rx)
+    }
+
+    // Queue a proof and start workers if any are free.
+    // Hands the job back if there is no room for it.
+    pub fn submit(&mut self, job: VerifyJob) -> Result<(), VerifyJob> {
+        let player_queued = self.queue.iter().filter(|queued| queued.player_id == job.player_id).count();
+        if self.queue.len() >= self.config.max_queued || player_queued >= MAX_QUEUED_PER_PLAYER {
+            return Err(job);
+        }
+        self.queue.push_back(job);
+        self.dispatch();
+        Ok(())
+    }
+
+    // Mark a player's verification as finished and hand out more work
+    pub fn complete(&mut self, player_id: &str) {
+        self.in_flight.remove(player_id);
+        self.dispatch();
+    }
+
+    // Number of proofs waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn dispatch(&mut self) {
+        while self.in_flight.len() < self.config.workers {
+            // Oldest job whose player isn't already being verified
+            let Some(index) = self
+                .queue
+                .iter()
+                .position(|job| !self.in_flight.contains(&job.player_id))
+            else {
+                break;
+            };
+            let job = self.queue.remove(index).unwrap();
+            self.in_flight.insert(job.player_id.clone());
+
+            let results_tx = self.results_tx.clone();
+            let accept_dev_receipts = self.config.accept_dev_receipts;
+            tokio::task::spawn_blocking(move || {
+                let outcome = VerifyOutcome {
+                    journal_digest: journal_digest(&job.receipt.journal.bytes),
+                    result: verify_receipt(&job.receipt, job.image_id, accept_dev_receipts),
+                    player_id: job.player_id,
+                    image_id: job.image_id,
+                    messages: job.messages,
+                    receipt: job.receipt,
+                };
+                if let Err(e) = results_tx.blocking_send(outcome) {
+                    eprintln!("Failed to return verification result: {:?}", e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::tests::fake_receipt;
+    use footsteps_methods::FOOTSTEPS_GUEST_ID;
+
+    #[test]
+    fn rejects_dev_receipts_unless_accepted() {
+        let receipt = fake_receipt();
+        assert!(matches!(verify_receipt(&receipt, FOOTSTEPS_GUEST_ID, false), Err(VerifyError::DevReceipt)));
+
+        // The claim checks out, so only the (empty) journal fails to decode
+        assert!(matches!(verify_receipt(&receipt, FOOTSTEPS_GUEST_ID, true), Err(VerifyError::Journal(_))));
+
+        let mut other_image = FOOTSTEPS_GUEST_ID;
+        other_image[0] ^= 1;
+        assert!(matches!(verify_receipt(&receipt, other_image, true), Err(VerifyError::Receipt(_))));
+    }
+
+    fn job(player_id: &str) -> VerifyJob {
+        VerifyJob {
+            player_id: player_id.to_string(),
+            receipt: fake_receipt(),
+            image_id: FOOTSTEPS_GUEST_ID,
+            messages: vec![(MessageId::new(b"proof"), PeerId::random())],
+        }
+    }
+
+    #[test]
+    fn turns_away_proofs_once_full() {
+        // No workers, so everything submitted stays queued
+        let config = VerifierConfig { workers: 0, max_queued: 6, accept_dev_receipts: false };
+        let (mut pool, _results) = VerifierPool::new(config);
+
+        // One player can't take the whole queue
+        for _ in 0..MAX_QUEUED_PER_PLAYER {
+            assert!(pool.submit(job("flooder")).is_ok());
+        }
+        assert!(pool.submit(job("flooder")).is_err());
+
+        assert!(pool.submit(job("alice")).is_ok());
+        assert!(pool.submit(job("bob")).is_ok());
+        let turned_away = pool.submit(job("carol")).unwrap_err();
+        assert_eq!(turned_away.player_id, "carol");
+        // Nothing already queued was given up
+        assert_eq!(pool.queued(), 6);
+    }
+}